@@ -1,9 +1,14 @@
 use directories::BaseDirs;
+use regex::Regex;
 use serde::Deserialize;
 use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io;
+use std::path::PathBuf;
 use std::sync::OnceLock;
 
+use crate::error::{Error, Result};
+
 #[derive(Deserialize, Debug)]
 pub struct WindowCommandMapping {
     pub class: Option<String>,
@@ -21,6 +26,57 @@ pub struct TerminalCommandMapping {
     pub command: Option<String>,
 }
 
+/// How to read the tabs and panes of a terminal, through its remote control interface.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminalBackend {
+    /// `kitty @ ls`, which needs `allow_remote_control` and `listen_on` in kitty.conf.
+    Kitty,
+    /// `wezterm cli list`.
+    Wezterm,
+}
+
+/// An app running all its windows in a single process, such that each window needs a process of
+/// its own to come back.
+#[derive(Deserialize, Debug)]
+pub struct MultiWindowApp {
+    /// Regex matched against the window class.
+    pub class: String,
+    /// Command opening one window, e.g. by asking the running instance for a new one. Without it,
+    /// the command of the process is run once per window.
+    pub command: Option<String>,
+}
+
+fn default_detect_environments() -> bool {
+    true
+}
+
+/// Sends the workspaces of a saved output matching all the given conditions to another output.
+#[derive(Deserialize, Debug)]
+pub struct OutputMapping {
+    /// Regex matched against the connector name of the saved output.
+    pub name: Option<String>,
+    /// `[width, height]` of the saved output.
+    pub resolution: Option<[u32; 2]>,
+    /// `[x, y]` of the top-left corner of the saved output.
+    pub position: Option<[i32; 2]>,
+    /// Connector name of the output to restore to, or `primary`.
+    pub output: String,
+}
+
+/// Where workspaces go when their saved output is gone and no mapping applies.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFallback {
+    /// Leave them wherever i3 put them.
+    #[default]
+    None,
+    /// Collapse them onto the primary output.
+    Primary,
+    /// Spread the missing outputs over the current ones, left to right.
+    RoundRobin,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Config {
     #[serde(default)]
@@ -30,27 +86,112 @@ pub struct Config {
     pub window_swallow_criteria: HashMap<String, HashSet<String>>,
     pub terminal_allow_revive_processes: HashSet<String>,
     pub terminal_revive_commands: HashMap<String, String>,
+    /// The backend reading the tabs and panes of terminals, by window class.
+    #[serde(default)]
+    pub terminal_backends: HashMap<String, TerminalBackend>,
+    /// Environment variables saved with each process, by name.
+    #[serde(default)]
+    pub environment_variables: HashSet<String>,
+    /// Whether to also save the variables of virtualenv, conda, nix-shell and direnv environments.
+    #[serde(default = "default_detect_environments")]
+    pub detect_environments: bool,
+    /// Empty by default, so that each app opts in.
+    #[serde(default)]
+    pub multi_window_apps: Vec<MultiWindowApp>,
+    #[serde(default)]
+    pub output_mappings: Vec<OutputMapping>,
+    #[serde(default)]
+    pub output_fallback: OutputFallback,
 }
 
-pub static CONFIG: OnceLock<Config> = OnceLock::new();
+// Private so that it can only be set by load_config, once the regexes are validated
+static CONFIG: OnceLock<Config> = OnceLock::new();
 
-pub fn load_config() {
+pub fn load_config() -> Result<()> {
     let default_config = Config {
         window_command_mappings: vec![],
         terminal_command_mappings: vec![],
         window_swallow_criteria: HashMap::new(),
         terminal_allow_revive_processes: HashSet::new(),
         terminal_revive_commands: HashMap::new(),
+        terminal_backends: HashMap::new(),
+        environment_variables: HashSet::new(),
+        detect_environments: default_detect_environments(),
+        multi_window_apps: vec![],
+        output_mappings: vec![],
+        output_fallback: OutputFallback::None,
+    };
+
+    let config = match BaseDirs::new() {
+        Some(base_dirs) => {
+            let config_path = base_dirs.config_dir().join("i3-revive/config.json");
+            match fs::read_to_string(&config_path) {
+                Ok(content) => serde_json::from_str(&content)
+                    .map_err(|e| Error::Config(format!("{}: {}", config_path.display(), e)))?,
+                Err(_) => default_config,
+            }
+        }
+        None => default_config,
     };
+    validate_config(&config)?;
+
+    CONFIG
+        .set(config)
+        .map_err(|_| Error::Config("config is already loaded".into()))
+}
+
+pub(crate) fn get_config() -> Result<&'static Config> {
+    CONFIG
+        .get()
+        .ok_or_else(|| Error::Config("config is not loaded".into()))
+}
 
-    if let Some(base_dirs) = BaseDirs::new() {
-        let config_path = base_dirs.config_dir().join("i3-revive/config.json");
-        let config = match fs::read_to_string(config_path) {
-            Ok(content) => serde_json::from_str(&content).unwrap(),
-            Err(_) => default_config,
-        };
-        CONFIG.set(config).unwrap();
-    } else {
-        CONFIG.set(default_config).unwrap();
+// Regexes are compiled where they are used, so make sure they all compile up front
+fn validate_config(config: &Config) -> Result<()> {
+    let regexes = config
+        .window_command_mappings
+        .iter()
+        .flat_map(|m| [&m.class, &m.title])
+        .chain(
+            config
+                .terminal_command_mappings
+                .iter()
+                .flat_map(|m| [&m.name, &m.args]),
+        )
+        .chain(config.output_mappings.iter().map(|m| &m.name))
+        .flatten()
+        .chain(config.multi_window_apps.iter().map(|app| &app.class))
+        .chain(config.window_swallow_criteria.keys());
+
+    for re in regexes {
+        Regex::new(re).map_err(|e| Error::Config(format!("invalid regex {:?}: {}", re, e)))?;
     }
+
+    Ok(())
+}
+
+pub(crate) fn base_dirs() -> Result<BaseDirs> {
+    BaseDirs::new().ok_or_else(|| {
+        Error::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Failed to get base directories",
+        ))
+    })
+}
+
+/// Returns `$XDG_DATA_HOME/i3-revive`.
+pub fn data_dir() -> Result<PathBuf> {
+    Ok(base_dirs()?.data_local_dir().join("i3-revive"))
+}
+
+/// Returns `$XDG_STATE_HOME/i3-revive`, where logs of revived processes are written.
+pub fn log_dir() -> Result<PathBuf> {
+    let base_dirs = base_dirs()?;
+    let state_dir = base_dirs.state_dir().ok_or_else(|| {
+        Error::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Failed to get state directory",
+        ))
+    })?;
+    Ok(state_dir.join("i3-revive"))
 }
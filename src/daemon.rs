@@ -0,0 +1,222 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::error::{Error, Result};
+use crate::i3_tree::get_all_windows;
+use crate::i3ipc::{
+    connect_i3, get_tree, subscribe, Event, EventType, MessageError, ShutdownChange, WindowChange,
+    WorkspaceChange,
+};
+use crate::session::{auto_backup, capture_session, Session, SessionData};
+
+const SUBSCRIBED_EVENTS: &[EventType] =
+    &[EventType::Window, EventType::Workspace, EventType::Shutdown];
+
+// How long to keep trying to reconnect after i3 restarts
+const RECONNECT_ATTEMPTS: u32 = 30;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How often the daemon saves the session.
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    /// Time since the last save after which the session is saved even without any change event,
+    /// e.g. to catch terminals that changed directory.
+    pub interval: Duration,
+    /// Time without any new change event to wait for before saving, so a burst of changes
+    /// results in a single save.
+    pub debounce: Duration,
+}
+
+impl Default for DaemonOptions {
+    fn default() -> Self {
+        DaemonOptions {
+            interval: Duration::from_secs(300),
+            debounce: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Keeps saving the session as i3 changes, until i3 exits.
+///
+/// Every save backs up the previous one, unless nothing has changed since. These backups are
+/// rotated apart from those of manual saves, so they don't push them out.
+pub fn run_daemon(session: &Session, options: &DaemonOptions) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    spawn_event_listener(tx.clone(), 1);
+
+    let mut last_saved = session
+        .load()
+        .ok()
+        .and_then(|data| session_content(&data).ok());
+    let mut last_save = Instant::now();
+    let mut changed_at: Option<Instant> = None;
+    let mut restarting = false;
+
+    // Start from a fresh save, so the interval counts from a known state
+    auto_save(session, &mut last_saved)?;
+
+    loop {
+        // Changes that never settle still get saved every interval
+        let until_interval = options.interval.saturating_sub(last_save.elapsed());
+        let timeout = match changed_at {
+            Some(changed_at) => options
+                .debounce
+                .saturating_sub(changed_at.elapsed())
+                .min(until_interval),
+            None => until_interval,
+        };
+
+        // Pending events would be received first, even once the save is due
+        let received = if timeout.is_zero() {
+            Err(RecvTimeoutError::Timeout)
+        } else {
+            rx.recv_timeout(timeout)
+        };
+
+        match received {
+            Ok(Ok(Event::Shutdown(info))) => {
+                if let Err(e) = auto_save(session, &mut last_saved) {
+                    eprintln!("Warning: Failed to save session {}: {}", session.name(), e);
+                }
+                last_save = Instant::now();
+                changed_at = None;
+
+                if info.change != ShutdownChange::Restart {
+                    return Ok(());
+                }
+                restarting = true;
+            }
+            Ok(Ok(event)) => {
+                if is_layout_change(&event) {
+                    changed_at = Some(Instant::now());
+                }
+            }
+            Ok(Err(e)) => {
+                if !restarting {
+                    return Err(e);
+                }
+
+                // The connection is closed when i3 restarts in place
+                restarting = false;
+                spawn_event_listener(tx.clone(), RECONNECT_ATTEMPTS);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if let Err(e) = auto_save(session, &mut last_saved) {
+                    eprintln!("Warning: Failed to save session {}: {}", session.name(), e);
+                }
+                last_save = Instant::now();
+                changed_at = None;
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!("a sender is kept alive"),
+        }
+    }
+}
+
+fn spawn_event_listener(tx: Sender<Result<Event>>, attempts: u32) {
+    thread::spawn(move || {
+        let mut stream = match connect_with_retries(attempts) {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                return;
+            }
+        };
+
+        let events = match subscribe(&mut stream, SUBSCRIBED_EVENTS) {
+            Ok(Some(events)) => events,
+            Ok(None) => {
+                let _ = tx.send(Err(Error::Ipc(MessageError::Receive(
+                    std::io::Error::other("i3 refused to subscribe to events"),
+                ))));
+                return;
+            }
+            Err(e) => {
+                let _ = tx.send(Err(e.into()));
+                return;
+            }
+        };
+
+        for event in events {
+            let is_err = event.is_err();
+            if tx.send(event.map_err(Error::from)).is_err() || is_err {
+                return;
+            }
+        }
+    });
+}
+
+fn connect_with_retries(attempts: u32) -> Result<std::os::unix::net::UnixStream> {
+    let mut attempt = 1;
+    loop {
+        match connect_i3() {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempt >= attempts => return Err(e.into()),
+            Err(_) => {
+                attempt += 1;
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+fn is_layout_change(event: &Event) -> bool {
+    match event {
+        Event::Window(info) => matches!(
+            info.change,
+            WindowChange::New
+                | WindowChange::Close
+                | WindowChange::Move
+                | WindowChange::Floating
+                | WindowChange::FullscreenMode
+                | WindowChange::Mark
+        ),
+        Event::Workspace(info) => matches!(
+            info.change,
+            WorkspaceChange::Init
+                | WorkspaceChange::Empty
+                | WorkspaceChange::Focus
+                | WorkspaceChange::Rename
+                | WorkspaceChange::Move
+        ),
+        _ => false,
+    }
+}
+
+// Saves the session unless a restore is in progress or nothing changed since the last save
+fn auto_save(session: &Session, last_saved: &mut Option<Value>) -> Result<()> {
+    let mut stream = connect_i3()?;
+
+    // Placeholders waiting to swallow a window would be saved as empty containers
+    let root = get_tree(&mut stream)?;
+    if get_all_windows(std::slice::from_ref(&root))
+        .iter()
+        .any(|win| win.is_placeholder)
+    {
+        return Ok(());
+    }
+
+    let data = capture_session(&mut stream, &[])?;
+    let content = session_content(&data)?;
+    if last_saved.as_ref() == Some(&content) {
+        return Ok(());
+    }
+
+    auto_backup(session)?;
+    session.save(&data)?;
+    *last_saved = Some(content);
+
+    Ok(())
+}
+
+// The session without its provenance, which changes on every save
+fn session_content(data: &SessionData) -> Result<Value> {
+    let mut content = serde_json::to_value(data).map_err(|e| Error::Session(e.to_string()))?;
+    if let Some(obj) = content.as_object_mut() {
+        obj.remove("provenance");
+    }
+
+    Ok(content)
+}
@@ -0,0 +1,85 @@
+use std::{fmt, io};
+
+use crate::i3ipc::MessageError;
+
+/// Any error that can happen while saving or restoring a session.
+#[derive(Debug)]
+pub enum Error {
+    /// Communication with i3 failed.
+    Ipc(MessageError),
+    /// Communication with the X server failed.
+    X11(xcb::Error),
+    /// A filesystem or process operation failed.
+    Io(io::Error),
+    /// The config file is invalid.
+    Config(String),
+    /// Information read from `/proc` couldn't be parsed.
+    Proc(String),
+    /// A saved session is missing or malformed.
+    Session(String),
+    /// Some placeholders were not filled when the restore timed out.
+    RestoreTimeout(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Ipc(e) => Some(e),
+            Error::X11(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Config(_) | Error::Proc(_) | Error::Session(_) | Error::RestoreTimeout(_) => {
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Ipc(e) => write!(f, "i3 IPC error: {}", e),
+            Error::X11(e) => write!(f, "X11 error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Config(msg) => write!(f, "Invalid config: {}", msg),
+            Error::Proc(msg) => write!(f, "Failed to read process info: {}", msg),
+            Error::Session(msg) => write!(f, "Invalid session: {}", msg),
+            Error::RestoreTimeout(count) => write!(
+                f,
+                "Restore timed out with {} placeholder(s) still waiting for a window",
+                count
+            ),
+        }
+    }
+}
+
+impl From<MessageError> for Error {
+    fn from(e: MessageError) -> Self {
+        Error::Ipc(e)
+    }
+}
+
+impl From<xcb::Error> for Error {
+    fn from(e: xcb::Error) -> Self {
+        Error::X11(e)
+    }
+}
+
+impl From<xcb::ConnError> for Error {
+    fn from(e: xcb::ConnError) -> Self {
+        Error::X11(e.into())
+    }
+}
+
+impl From<xcb::ProtocolError> for Error {
+    fn from(e: xcb::ProtocolError) -> Self {
+        Error::X11(e.into())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -1,396 +1,788 @@
 use std::{
     collections::HashSet,
-    error::Error,
-    fs,
+    env, fs,
     io::{BufWriter, Write},
-    os::unix::net::UnixStream,
+    os::unix::{fs::DirBuilderExt, net::UnixStream},
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use directories::BaseDirs;
 use regex::{escape, Regex};
-use serde_json::{Map, Value};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use xcb::{x, XidNew};
 
 use crate::{
-    config::CONFIG,
-    i3ipc::{get_tree, get_workspaces, run_command},
+    config::{get_config, Config},
+    error::{Error, Result},
+    i3ipc::{get_tree, get_workspaces, run_command, Node, NodeType, WindowProperties},
+    restore::RestoreOptions,
 };
 
+// Marks the placeholder an adopted window is moved next to
+const ADOPT_MARK: &str = "_i3-revive-adopt";
+
 #[derive(Debug)]
 pub struct Window {
     pub id: u32,
     pub name: String,
     pub class: Option<String>,
+    pub properties: WindowProperties,
+    /// The workspace the window is on, or `None` for the scratchpad.
+    pub workspace: Option<String>,
     pub is_placeholder: bool,
 }
 
-// https://github.com/i3/i3/blob/2746e0319b03a8a5a02b57a69b1fb47e0a9c22f1/i3-save-tree#L88
-const ALLOWED_KEYS: &[&str] = &[
-    "type",
-    "fullscreen_mode",
-    "layout",
-    "border",
-    "current_border_width",
-    "floating",
-    "percent",
-    "nodes",
-    "floating_nodes",
-    "name",
-    "geometry",
-    "window_properties",
-    "marks",
-    "rect",
-];
-
-pub fn find_workspaces(mut tree: Value) -> Vec<Value> {
+/// The layout of a workspace, in the format expected by `append_layout`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkspaceLayout {
+    pub name: String,
+    /// The top-level containers of the workspace, tiling ones first.
+    pub nodes: Vec<Value>,
+    /// The focus chain of the workspace, as the index of the focused child at each level, from
+    /// the top-level containers down to the focused window.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub focus_path: Vec<usize>,
+}
+
+/// A container created by `append_layout`, along with the saved container it was created from.
+///
+/// Placeholders keep their id when they swallow a window, so it can be used to act on the revived
+/// window.
+#[derive(Debug, Clone)]
+pub struct AppendedContainer<'a> {
+    pub id: u64,
+    pub layout: &'a Value,
+    /// The workspace it was appended to, or `None` for the scratchpad.
+    pub workspace: Option<&'a str>,
+    /// Whether an already running window took the place of this placeholder, in which case `id`
+    /// is the container of that window.
+    pub adopted: bool,
+}
+
+pub fn find_workspaces(tree: Node) -> Vec<Node> {
     if is_normal_workspace(&tree) {
         return vec![tree];
     }
 
-    let mut res: Vec<Value> = vec![];
-    run_for_all_nodes_mut(&mut tree, |v| {
-        for child in v.as_array_mut().unwrap().drain(..) {
-            res.append(&mut find_workspaces(child))
-        }
-    });
+    tree.nodes
+        .into_iter()
+        .chain(tree.floating_nodes)
+        .flat_map(find_workspaces)
+        .collect()
+}
 
-    res
+/// Returns the container with the given id.
+pub fn find_node(tree: &Node, id: u64) -> Option<&Node> {
+    if tree.id == id {
+        return Some(tree);
+    }
+
+    tree.children().find_map(|child| find_node(child, id))
 }
 
-pub fn get_all_windows(trees: &Vec<Value>) -> Vec<Window> {
-    let mut res: Vec<Window> = vec![];
-
-    for tree in trees {
-        if let (Some(id), Some(name), Some(props)) = (
-            tree.get("window"),
-            tree.get("name"),
-            tree.get("window_properties"),
-        ) {
-            res.push(Window {
-                id: id.as_u64().unwrap() as u32,
-                name: name.as_str().unwrap().to_string(),
-                class: props
-                    .get("class")
-                    .and_then(|class| class.as_str())
-                    .map(|str| str.to_string()),
-                is_placeholder: tree
-                    .get("swallows")
-                    .is_some_and(|swallows| !swallows.as_array().unwrap().is_empty()),
-            })
-        };
+/// Returns the hidden workspace holding the scratchpad containers.
+pub fn find_scratchpad(tree: &Node) -> Option<&Node> {
+    if is_scratchpad(tree) {
+        return Some(tree);
+    }
 
-        run_for_all_nodes(tree, |v| {
-            res.append(&mut get_all_windows(v.as_array().unwrap()));
-        });
+    tree.children().find_map(find_scratchpad)
+}
+
+pub fn get_all_windows(trees: &[Node]) -> Vec<Window> {
+    fn collect(trees: &[Node], workspace: Option<&str>, res: &mut Vec<Window>) {
+        for tree in trees {
+            let workspace = match tree.node_type {
+                NodeType::Workspace if is_normal_workspace(tree) => tree.name.as_deref(),
+                NodeType::Workspace => None,
+                _ => workspace,
+            };
+
+            if let (Some(id), Some(name), Some(props)) =
+                (tree.window, &tree.name, &tree.window_properties)
+            {
+                res.push(Window {
+                    id,
+                    name: name.clone(),
+                    class: props.class.clone(),
+                    properties: props.clone(),
+                    workspace: workspace.map(|ws| ws.to_string()),
+                    is_placeholder: !tree.swallows.is_empty(),
+                })
+            };
+
+            collect(&tree.nodes, workspace, res);
+            collect(&tree.floating_nodes, workspace, res);
+        }
     }
 
+    let mut res = vec![];
+    collect(trees, None, &mut res);
+
     res
 }
 
-pub fn save_workspaces(mut workspaces: Vec<Value>) {
-    let base_dirs = BaseDirs::new().expect("Failed to get base directories");
-    let mut dir = base_dirs.data_local_dir().to_path_buf();
-    dir.push("i3-revive");
-    dir.push("layouts");
-    fs::create_dir_all(&dir).expect("Failed to create directory");
-
-    for ws in workspaces.iter_mut() {
-        let ws_name_node = ws.get("name").unwrap().to_owned();
-        let ws_name = ws_name_node.as_str().unwrap();
-
-        convert_to_layout(ws);
-
-        let mut file_path = dir.clone();
-        file_path.push(format!("ws_{}.json", ws_name));
-        let f = fs::File::create(file_path).expect("Failed to create file");
-        let mut f = BufWriter::new(f);
-
-        run_for_all_nodes(ws, |v| {
-            for child in v.as_array().unwrap().iter() {
-                writeln!(
-                    f,
-                    "{}",
-                    serde_json::to_string_pretty(child).expect("Failed to serialize")
-                )
-                .expect("Failed to write to file");
+/// A container of a saved layout that will wait to swallow a window once appended.
+#[derive(Debug, Clone)]
+pub struct LayoutPlaceholder {
+    pub name: Option<String>,
+    pub swallows: Vec<Map<String, Value>>,
+}
+
+/// Returns the placeholders of saved containers, in tree order.
+pub fn get_layout_placeholders(nodes: &[Value]) -> Vec<LayoutPlaceholder> {
+    fn collect(node: &Value, res: &mut Vec<LayoutPlaceholder>) {
+        if let Some(swallows) = node.get("swallows").and_then(|s| s.as_array()) {
+            res.push(LayoutPlaceholder {
+                name: node
+                    .get("name")
+                    .and_then(|name| name.as_str())
+                    .map(|name| name.to_string()),
+                swallows: swallows
+                    .iter()
+                    .filter_map(|criteria| criteria.as_object().cloned())
+                    .collect(),
+            });
+        }
+
+        for key in ["nodes", "floating_nodes"] {
+            if let Some(children) = node.get(key).and_then(|c| c.as_array()) {
+                for child in children {
+                    collect(child, res);
+                }
             }
-        });
+        }
+    }
+
+    let mut res = vec![];
+    for node in nodes {
+        collect(node, &mut res);
     }
+
+    res
 }
 
-pub fn restore_workspaces(stream: &mut UnixStream) {
-    let base_dirs = BaseDirs::new().expect("Failed to get base directories");
-    let mut dir = base_dirs.data_local_dir().to_path_buf();
-    dir.push("i3-revive");
-    dir.push("layouts");
+pub fn save_workspaces(workspaces: &[Node]) -> Result<Vec<WorkspaceLayout>> {
+    let config = get_config()?;
 
-    if !dir.exists() {
-        eprintln!("Layouts directory is missing");
-        std::process::exit(1);
+    Ok(workspaces
+        .iter()
+        .map(|ws| WorkspaceLayout {
+            name: ws.name.clone().unwrap_or_default(),
+            nodes: ws
+                .children()
+                .map(|child| convert_to_layout(child, config))
+                .collect(),
+            focus_path: get_focus_path(ws),
+        })
+        .collect())
+}
+
+// Follows the first entry of each focus array, which is the child that has or last had the focus
+fn get_focus_path(node: &Node) -> Vec<usize> {
+    let mut path = vec![];
+    let mut node = node;
+    while let Some(focused_id) = node.focus.first() {
+        let Some((i, child)) = node
+            .children()
+            .enumerate()
+            .find(|(_, child)| child.id == *focused_id)
+        else {
+            break;
+        };
+
+        path.push(i);
+        node = child;
     }
 
-    let root = get_tree(stream).expect("Failed to get tree");
-    let (conn, _) = xcb::Connection::connect(None).expect("Failed to connect to X server");
+    path
+}
+
+/// Saves the scratchpad containers, which are all floating.
+pub fn save_scratchpad(scratchpad: &Node) -> Result<Vec<Value>> {
+    let config = get_config()?;
+
+    Ok(scratchpad
+        .floating_nodes
+        .iter()
+        .map(|child| convert_to_layout(child, config))
+        .collect())
+}
 
-    let tree_workspaces = find_workspaces(root);
+/// Appends the saved layouts to their workspaces, then the scratchpad containers to the focused
+/// workspace, from which they are immediately moved back to the scratchpad. Their placeholders
+/// swallow windows there, so these stay hidden.
+///
+/// Existing windows are unmapped meanwhile, so i3 manages them anew once they're mapped back and
+/// lets matching placeholders swallow them. When merging, they are left alone for
+/// [`adopt_windows`] to handle instead. When only some workspaces are restored, windows on the
+/// others aren't touched.
+///
+/// Returns the containers created along the way.
+pub fn restore_workspaces<'a>(
+    stream: &mut UnixStream,
+    layouts: &'a [WorkspaceLayout],
+    scratchpad: &'a [Value],
+    options: &RestoreOptions,
+) -> Result<Vec<AppendedContainer<'a>>> {
+    let merge = options.merge;
+    let root = get_tree(stream)?;
+    let (conn, _) = xcb::Connection::connect(None)?;
+
+    let tree_workspaces = find_workspaces(root)
+        .into_iter()
+        .filter(|ws| {
+            options.workspaces.is_empty()
+                || ws
+                    .name
+                    .as_ref()
+                    .is_some_and(|name| options.workspaces.contains(name))
+        })
+        .collect::<Vec<_>>();
     let windows = get_all_windows(&tree_workspaces);
-    let workspaces = get_workspaces(stream).expect("Failed to get workspaces");
+    let workspaces = get_workspaces(stream)?;
     let focused_workspace_name = workspaces
-        .as_array()
-        .unwrap()
         .iter()
-        .find(|ws| {
-            ws.get("focused")
-                .and_then(|focused| focused.as_bool())
-                .unwrap_or(false)
-        })
-        .map(|ws| ws.get("name").unwrap().as_str().unwrap());
-
-    for window in &windows {
-        if window.is_placeholder {
-            conn.send_and_check_request(&x::KillClient {
-                resource: window.id,
-            })
-            .unwrap();
-        } else {
-            conn.send_and_check_request(&x::UnmapWindow {
-                window: unsafe { XidNew::new(window.id) },
-            })
-            .unwrap();
-        };
-    }
+        .find(|ws| ws.focused)
+        .map(|ws| ws.name.as_str());
+
+    // append_layout only reads from files, so write the layouts somewhere temporary
+    let dir = create_layouts_dir()?;
+
+    let mut unmapped = vec![];
+    let mut do_things = || -> Result<Vec<AppendedContainer<'a>>> {
+        for window in &windows {
+            if window.is_placeholder {
+                kill_window(&conn, window)?;
+            } else if !merge {
+                conn.send_and_check_request(&x::UnmapWindow {
+                    window: XidNew::new(window.id),
+                })?;
+                unmapped.push(window.id);
+            };
+        }
 
-    let do_things = || -> Result<(), Box<dyn Error>> {
-        let ws_name_re = Regex::new(r"^ws_(.+).json$").unwrap();
-        let mut first_entry = true;
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let file_name = entry
-                .file_name()
-                .into_string()
-                .map_err(|os_str| format!("Failed to convert file name to string: {:?}", os_str))?;
-            let path = entry
-                .path()
-                .into_os_string()
-                .into_string()
-                .map_err(|os_str| format!("Failed to convert path to string: {:?}", os_str))?;
-
-            if let Some(caps) = ws_name_re.captures(&file_name) {
-                let ws_name = &caps[1];
-
-                if !first_entry || focused_workspace_name.is_none_or(|name| name != ws_name) {
-                    run_command(stream, format!("workspace {}", ws_name).as_str())?;
-                }
+        let mut appended = vec![];
+        for (i, layout) in layouts.iter().enumerate() {
+            let path = dir.join(format!("ws_{}.json", i));
+            write_layout_file(&path, &layout.nodes)?;
+            let path = path.to_str().ok_or_else(|| {
+                Error::Session(format!("Failed to convert path to string: {:?}", path))
+            })?;
+
+            if i != 0 || focused_workspace_name.is_none_or(|name| name != layout.name) {
+                run_command(
+                    stream,
+                    format!("workspace {}", quote(&layout.name)).as_str(),
+                )?;
+            }
 
-                run_command(stream, format!("append_layout {}", path).as_str())?;
+            run_command(stream, format!("append_layout {}", quote(path)).as_str())?;
 
-                first_entry = false;
+            let root = get_tree(stream)?;
+            if let Some(ws) = find_focused_workspace(&root) {
+                let (floating, tiling): (Vec<_>, Vec<_>) = layout
+                    .nodes
+                    .iter()
+                    .partition(|node| node["type"] == json!(NodeType::FloatingCon));
+                let workspace = Some(layout.name.as_str());
+                match_appended(&tiling, &ws.nodes, workspace, &mut appended);
+                match_appended(&floating, &ws.floating_nodes, workspace, &mut appended);
             }
         }
-        Ok(())
+
+        if !scratchpad.is_empty() {
+            restore_scratchpad(stream, &dir, scratchpad, &mut appended)?;
+        }
+        Ok(appended)
     };
 
-    let has_err = if let Err(err) = do_things() {
-        eprintln!("Failed to do things: {}", err);
-        true
-    } else {
-        false
+    let mut res = do_things();
+
+    // Remap the existing windows even if restoring failed, so they don't stay hidden. A window
+    // may have closed meanwhile, which mustn't keep the others hidden either
+    for id in unmapped {
+        if let Err(e) = conn.send_and_check_request(&x::MapWindow {
+            window: XidNew::new(id),
+        }) {
+            eprintln!("Warning: Failed to map window {} back: {}", id, e);
+            if res.is_ok() {
+                res = Err(e.into());
+            }
+        }
+    }
+
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        eprintln!(
+            "Warning: Failed to remove layouts directory {:?}: {}",
+            dir, e
+        );
+    }
+
+    res
+}
+
+/// Moves already running windows into the appended placeholders whose swallow criteria they
+/// match, closing these placeholders, so that no new instance has to be spawned for them.
+///
+/// Returns the X window ids of the adopted windows.
+pub fn adopt_windows(
+    stream: &mut UnixStream,
+    appended: &mut [AppendedContainer],
+) -> Result<Vec<u32>> {
+    let root = get_tree(stream)?;
+    let appended_ids = appended
+        .iter()
+        .map(|container| container.id)
+        .collect::<HashSet<_>>();
+
+    fn collect_windows<'n>(node: &'n Node, res: &mut Vec<&'n Node>) {
+        if node.window.is_some() && node.window_properties.is_some() && node.swallows.is_empty() {
+            res.push(node);
+        }
+        for child in node.children() {
+            collect_windows(child, res);
+        }
+    }
+    let mut running = vec![];
+    collect_windows(&root, &mut running);
+    running.retain(|node| !appended_ids.contains(&node.id));
+
+    let mut adopted = vec![];
+    for container in appended.iter_mut() {
+        let Some(swallows) = container
+            .layout
+            .get("swallows")
+            .and_then(|swallows| swallows.as_array())
+        else {
+            continue;
+        };
+
+        let Some(i) = running.iter().position(|node| {
+            swallows
+                .iter()
+                .filter_map(|criteria| criteria.as_object())
+                .any(|criteria| matches_swallows(node, criteria))
+        }) else {
+            continue;
+        };
+        let window = running.remove(i);
+
+        // The window takes the place of the placeholder by moving next to it, then closing it
+        run_command(
+            stream,
+            format!("[con_id={}] mark --add {}", container.id, ADOPT_MARK).as_str(),
+        )?;
+        run_command(
+            stream,
+            format!(
+                "[con_id={}] move container to mark {}",
+                window.id, ADOPT_MARK
+            )
+            .as_str(),
+        )?;
+        run_command(stream, format!("[con_id={}] kill", container.id).as_str())?;
+
+        container.id = window.id;
+        container.adopted = true;
+        adopted.extend(window.window);
+    }
+
+    Ok(adopted)
+}
+
+// Swallow criteria are regexes on window properties, all of which must match
+fn matches_swallows(node: &Node, criteria: &Map<String, Value>) -> bool {
+    let Some(props) = &node.window_properties else {
+        return false;
     };
 
-    for window in &windows {
-        if !window.is_placeholder {
-            conn.send_and_check_request(&x::MapWindow {
-                window: unsafe { XidNew::new(window.id) },
-            })
-            .unwrap();
+    !criteria.is_empty()
+        && criteria.iter().all(|(key, pattern)| {
+            let value = match key.as_str() {
+                "class" => &props.class,
+                "instance" => &props.instance,
+                "title" => &props.title,
+                "window_role" => &props.window_role,
+                _ => return false,
+            };
+
+            match (value, pattern.as_str()) {
+                (Some(value), Some(pattern)) => {
+                    Regex::new(pattern).is_ok_and(|re| re.is_match(value))
+                }
+                _ => false,
+            }
+        })
+}
+
+/// Closes placeholders, e.g. those whose process never opened a window.
+pub fn kill_placeholders(placeholders: &[Window]) -> Result<()> {
+    let (conn, _) = xcb::Connection::connect(None)?;
+    for placeholder in placeholders {
+        kill_window(&conn, placeholder)?;
+    }
+
+    Ok(())
+}
+
+fn kill_window(conn: &xcb::Connection, window: &Window) -> Result<()> {
+    conn.send_and_check_request(&x::KillClient {
+        resource: window.id,
+    })?;
+
+    Ok(())
+}
+
+fn restore_scratchpad<'a>(
+    stream: &mut UnixStream,
+    dir: &Path,
+    scratchpad: &'a [Value],
+    appended: &mut Vec<AppendedContainer<'a>>,
+) -> Result<()> {
+    let path = dir.join("scratchpad.json");
+    write_layout_file(&path, scratchpad)?;
+    let path = path
+        .to_str()
+        .ok_or_else(|| Error::Session(format!("Failed to convert path to string: {:?}", path)))?;
+
+    // The appended containers are the floating ones that weren't there before
+    let existing_ids: HashSet<u64> = find_focused_workspace(&get_tree(stream)?)
+        .map(|ws| ws.floating_nodes.iter().map(|node| node.id).collect())
+        .unwrap_or_default();
+    run_command(stream, format!("append_layout {}", quote(path)).as_str())?;
+
+    let root = get_tree(stream)?;
+    let new_nodes = find_focused_workspace(&root)
+        .map(|ws| {
+            ws.floating_nodes
+                .iter()
+                .filter(|node| !existing_ids.contains(&node.id))
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    for node in &new_nodes {
+        run_command(
+            stream,
+            format!("[con_id={}] move scratchpad", node.id).as_str(),
+        )?;
+    }
+    match_appended(
+        &scratchpad.iter().collect::<Vec<_>>(),
+        &new_nodes,
+        None,
+        appended,
+    );
+
+    Ok(())
+}
+
+// Pairs saved containers with the live ones append_layout created from them, which come last
+// among their siblings
+fn match_appended<'a>(
+    saved: &[&'a Value],
+    live: &[Node],
+    workspace: Option<&'a str>,
+    res: &mut Vec<AppendedContainer<'a>>,
+) {
+    let Some(live) = live.get(live.len().saturating_sub(saved.len())..) else {
+        return;
+    };
+    if live.len() != saved.len() {
+        return;
+    }
+
+    for (saved, live) in saved.iter().zip(live) {
+        res.push(AppendedContainer {
+            id: live.id,
+            layout: saved,
+            workspace,
+            adopted: false,
+        });
+
+        for (key, live_children) in [
+            ("nodes", &live.nodes),
+            ("floating_nodes", &live.floating_nodes),
+        ] {
+            let saved_children = saved
+                .get(key)
+                .and_then(|c| c.as_array())
+                .map(|c| c.iter().collect::<Vec<_>>())
+                .unwrap_or_default();
+            match_appended(&saved_children, live_children, workspace, res);
         }
     }
+}
 
-    if has_err {
-        std::process::exit(1);
+/// Re-applies the saved marks of the appended containers, once their placeholders have swallowed
+/// a window. i3 drops the marks of a placeholder when it swallows a window.
+pub fn restore_marks(stream: &mut UnixStream, appended: &[AppendedContainer]) -> Result<()> {
+    for container in appended {
+        let marks = container
+            .layout
+            .get("marks")
+            .and_then(|marks| marks.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|mark| mark.as_str());
+
+        for mark in marks {
+            run_command(
+                stream,
+                format!("[con_id={}] mark --add {}", container.id, quote(mark)).as_str(),
+            )?;
+        }
     }
+
+    Ok(())
 }
 
-pub fn remove_workspaces() {
-    let base_dirs = BaseDirs::new().expect("Failed to get base directories");
-    let mut dir = base_dirs.data_local_dir().to_path_buf();
-    dir.push("i3-revive");
-    dir.push("layouts");
+/// Focuses the container that was focused on each workspace, leaving the last restored workspace
+/// focused.
+pub fn restore_focus(
+    stream: &mut UnixStream,
+    layouts: &[WorkspaceLayout],
+    appended: &[AppendedContainer],
+) -> Result<()> {
+    for layout in layouts {
+        let Some((&first, rest)) = layout.focus_path.split_first() else {
+            continue;
+        };
+
+        // Layouts list the children of a container the same way, tiling ones first
+        let mut focused = layout.nodes.get(first);
+        for &i in rest {
+            focused = focused.and_then(|node| {
+                ["nodes", "floating_nodes"]
+                    .iter()
+                    .filter_map(|key| node.get(key).and_then(|c| c.as_array()))
+                    .flatten()
+                    .nth(i)
+            });
+        }
 
-    if dir.exists() {
-        fs::remove_dir_all(&dir).expect("Failed to remove layouts directory");
+        let container = focused.and_then(|focused| {
+            appended
+                .iter()
+                .find(|container| std::ptr::eq(container.layout, focused))
+        });
+        if let Some(container) = container {
+            run_command(stream, format!("[con_id={}] focus", container.id).as_str())?;
+        }
     }
+
+    Ok(())
 }
 
-// https://github.com/i3/i3/blob/2746e0319b03a8a5a02b57a69b1fb47e0a9c22f1/i3-save-tree#L105
-fn convert_to_layout(tree: &mut Value) {
-    let config = CONFIG.get().unwrap();
-    let tree_obj = tree.as_object_mut().unwrap();
-    let is_tree_leaf_node = is_leaf_node(tree_obj);
-    let leaf_node_id = tree_obj.get("window").and_then(|win| win.as_u64());
+/// Quotes an argument of an i3 command.
+pub(crate) fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
 
-    // layout is not relevant for a leaf container
-    if is_tree_leaf_node {
-        tree_obj.remove("layout");
+fn find_focused_workspace(tree: &Node) -> Option<&Node> {
+    if tree.node_type == NodeType::Workspace {
+        return Some(tree);
+    }
+
+    // The first entry of focus is the child that has or last had the focus
+    let focused_id = tree.focus.first()?;
+    tree.children()
+        .find(|child| child.id == *focused_id)
+        .and_then(find_focused_workspace)
+}
+
+// append_layout expects the containers as concatenated JSON objects, not as an array
+// i3 appends whatever layouts the directory holds, so it must be a fresh one only we can write
+// to, rather than one someone else could have planted at a predictable path
+fn create_layouts_dir() -> Result<PathBuf> {
+    let parent = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let dir = parent.join(format!("i3-revive-{}-{}", process::id(), nanos));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    Ok(dir)
+}
+
+fn write_layout_file(path: &Path, nodes: &[Value]) -> Result<()> {
+    let f = fs::File::create(path)?;
+    let mut f = BufWriter::new(f);
+
+    for node in nodes {
+        writeln!(
+            f,
+            "{}",
+            serde_json::to_string_pretty(node).map_err(|e| Error::Session(e.to_string()))?
+        )?;
     }
+    f.flush()?;
+
+    Ok(())
+}
+
+// https://github.com/i3/i3/blob/2746e0319b03a8a5a02b57a69b1fb47e0a9c22f1/i3-save-tree#L105
+//
+// Only the keys allowed by i3-save-tree are emitted:
+// https://github.com/i3/i3/blob/2746e0319b03a8a5a02b57a69b1fb47e0a9c22f1/i3-save-tree#L88
+fn convert_to_layout(tree: &Node, config: &Config) -> Value {
+    let is_tree_leaf_node = tree.is_leaf();
+    let mut layout = Map::new();
+
+    layout.insert("type".to_string(), json!(tree.node_type));
 
     // fullscreen_mode conveys no state at all, it can be 0, 1, or 2 and the
     // default is _always_ 0, so skip noop entries.
-    if tree_obj.get("fullscreen_mode").unwrap().as_u64().unwrap() == 0 {
-        tree_obj.remove("fullscreen_mode");
+    if tree.fullscreen_mode != 0 {
+        layout.insert("fullscreen_mode".to_string(), json!(tree.fullscreen_mode));
     }
 
-    // names for non-leafs are auto-generated and useful only for i3 debugging
+    // layout is not relevant for a leaf container
     if !is_tree_leaf_node {
-        tree_obj.remove("name");
+        layout.insert("layout".to_string(), json!(tree.layout));
     }
 
-    if is_zero_rect(tree_obj.get("geometry").unwrap().as_object().unwrap()) {
-        tree_obj.remove("geometry");
-    };
+    layout.insert("border".to_string(), json!(tree.border));
 
-    // Retain the rect for floating containers to keep their positions
-    if tree_obj.get("type").unwrap().as_str().unwrap() != "floating_con" {
-        tree_obj.remove("rect");
+    if tree.current_border_width != -1 {
+        layout.insert(
+            "current_border_width".to_string(),
+            json!(tree.current_border_width),
+        );
     }
 
-    if tree_obj
-        .get("current_border_width")
-        .unwrap()
-        .as_i64()
-        .unwrap()
-        == -1
-    {
-        tree_obj.remove("current_border_width");
+    if let Some(floating) = &tree.floating {
+        layout.insert("floating".to_string(), json!(floating));
     }
 
-    if tree_obj
-        .get("nodes")
-        .is_some_and(|v| v.as_array().unwrap().is_empty())
-    {
-        tree_obj.remove("nodes");
+    layout.insert("percent".to_string(), json!(tree.percent));
+
+    // names for non-leafs are auto-generated and useful only for i3 debugging
+    if is_tree_leaf_node {
+        layout.insert("name".to_string(), json!(tree.name));
     }
 
-    if tree_obj
-        .get("floating_nodes")
-        .is_some_and(|v| v.as_array().unwrap().is_empty())
-    {
-        tree_obj.remove("floating_nodes");
+    if !tree.geometry.is_zero() {
+        layout.insert("geometry".to_string(), json!(tree.geometry));
     }
 
-    let keys = tree_obj
-        .iter()
-        .map(|(key, _)| key.to_owned())
-        .collect::<Vec<_>>();
-    for key in keys {
-        if !ALLOWED_KEYS.contains(&key.as_str()) {
-            tree_obj.remove(&key);
-        }
+    layout.insert("marks".to_string(), json!(tree.marks));
+
+    // Retain the rect for floating containers to keep their positions
+    if tree.node_type == NodeType::FloatingCon {
+        layout.insert("rect".to_string(), json!(tree.rect));
     }
 
     // https://github.com/i3/i3/blob/2746e0319b03a8a5a02b57a69b1fb47e0a9c22f1/i3-save-tree#L167
     // Turn "window_properties" into "swallows" expressions, but only for leaf
     // nodes. It only makes sense for leaf nodes to swallow anything.
     if is_tree_leaf_node {
-        if let Some(window_properties) = tree_obj.get("window_properties") {
-            let props_obj = window_properties.as_object().unwrap();
-            let mut swallows = Map::new();
-            let mut criteria: Option<&HashSet<String>> = None;
-            let mut is_terminal = false;
-
-            if let Some(class) = props_obj.get("class") {
-                let class = class.as_str().unwrap();
-                criteria = config
-                    .window_swallow_criteria
-                    .iter()
-                    .find(|(k, _)| Regex::new(k).unwrap().is_match(class))
-                    .map(|(_, v)| v);
-
-                if criteria.is_none_or(|crit| crit.contains("class")) {
-                    swallows.insert(
-                        "class".to_string(),
-                        Value::String(format!("^{}$", escape(class).as_str())),
-                    );
-                }
+        if let Some(props) = &tree.window_properties {
+            layout.insert(
+                "swallows".to_string(),
+                Value::Array(vec![Value::Object(get_swallow_criteria(
+                    tree.window,
+                    props,
+                    config,
+                ))]),
+            );
+        }
+    }
 
-                is_terminal = config.terminal_revive_commands.contains_key(class);
-            }
+    if !tree.nodes.is_empty() {
+        layout.insert(
+            "nodes".to_string(),
+            Value::Array(
+                tree.nodes
+                    .iter()
+                    .map(|child| convert_to_layout(child, config))
+                    .collect(),
+            ),
+        );
+    }
 
-            if let Some(instance) = props_obj.get("instance") {
-                if criteria.is_none_or(|crit| crit.contains("instance")) {
-                    swallows.insert(
-                        "instance".to_string(),
-                        Value::String(format!("^{}$", escape(instance.as_str().unwrap()).as_str())),
-                    );
-                }
-            }
+    if !tree.floating_nodes.is_empty() {
+        layout.insert(
+            "floating_nodes".to_string(),
+            Value::Array(
+                tree.floating_nodes
+                    .iter()
+                    .map(|child| convert_to_layout(child, config))
+                    .collect(),
+            ),
+        );
+    }
 
-            if let Some(title) = props_obj.get("title") {
-                let title = title.as_str().unwrap();
-                if criteria.is_some_and(|crit| crit.contains("title")) {
-                    swallows.insert(
-                        "title".to_string(),
-                        Value::String(format!("^{}$", escape(title).as_str())),
-                    );
-                } else if is_terminal {
-                    swallows.insert(
-                        "title".to_string(),
-                        Value::String(format!("Revive-Terminal-Window-{}", leaf_node_id.unwrap(),)),
-                    );
-                }
-            }
+    Value::Object(layout)
+}
 
-            tree_obj.insert(
-                "swallows".to_string(),
-                Value::Array(vec![Value::Object(swallows)]),
+/// Returns the criteria a placeholder uses to swallow the given window, as configured by
+/// `window_swallow_criteria`.
+pub(crate) fn get_swallow_criteria(
+    window: Option<u32>,
+    props: &WindowProperties,
+    config: &Config,
+) -> Map<String, Value> {
+    let mut swallows = Map::new();
+    let mut criteria: Option<&HashSet<String>> = None;
+    let mut is_terminal = false;
+
+    if let Some(class) = &props.class {
+        criteria = config
+            .window_swallow_criteria
+            .iter()
+            .find(|(k, _)| Regex::new(k).unwrap().is_match(class))
+            .map(|(_, v)| v);
+
+        if criteria.is_none_or(|crit| crit.contains("class")) {
+            swallows.insert(
+                "class".to_string(),
+                Value::String(format!("^{}$", escape(class).as_str())),
             );
         }
+
+        is_terminal = config.terminal_revive_commands.contains_key(class);
     }
-    tree_obj.remove("window_properties");
 
-    run_for_all_nodes_mut(tree, |v| {
-        for child in v.as_array_mut().unwrap().iter_mut() {
-            convert_to_layout(child)
+    if let Some(instance) = &props.instance {
+        if criteria.is_none_or(|crit| crit.contains("instance")) {
+            swallows.insert(
+                "instance".to_string(),
+                Value::String(format!("^{}$", escape(instance).as_str())),
+            );
         }
-    });
-}
-
-fn run_for_all_nodes(tree: &Value, mut cb: impl FnMut(&Value)) {
-    if let Some(v) = tree.get("nodes") {
-        cb(v);
     }
-    if let Some(v) = tree.get("floating_nodes") {
-        cb(v);
-    }
-}
 
-fn run_for_all_nodes_mut(tree: &mut Value, mut cb: impl FnMut(&mut Value)) {
-    if let Some(v) = tree.get_mut("nodes") {
-        cb(v);
-    }
-    if let Some(v) = tree.get_mut("floating_nodes") {
-        cb(v);
+    if let Some(title) = &props.title {
+        if criteria.is_some_and(|crit| crit.contains("title")) {
+            swallows.insert(
+                "title".to_string(),
+                Value::String(format!("^{}$", escape(title).as_str())),
+            );
+        } else if is_terminal {
+            if let Some(window_id) = window {
+                swallows.insert(
+                    "title".to_string(),
+                    Value::String(format!("Revive-Terminal-Window-{}", window_id)),
+                );
+            }
+        }
     }
-}
 
-fn is_normal_workspace(val: &Value) -> bool {
-    val.get("type").unwrap().as_str().unwrap() == "workspace"
-        && val.get("name").is_some_and(|n| n != "__i3_scratch")
+    swallows
 }
 
-fn is_leaf_node(val: &Map<String, Value>) -> bool {
-    val.get("type").unwrap().as_str().unwrap() == "con"
-        && val
-            .get("nodes")
-            .is_none_or(|ns| ns.as_array().unwrap().is_empty())
-        && val
-            .get("floating_nodes")
-            .is_none_or(|ns| ns.as_array().unwrap().is_empty())
+fn is_normal_workspace(node: &Node) -> bool {
+    node.node_type == NodeType::Workspace && !is_scratchpad(node)
 }
 
-fn is_zero_rect(val: &Map<String, Value>) -> bool {
-    val.get("x").unwrap().as_u64().unwrap() == 0
-        && val.get("y").unwrap().as_u64().unwrap() == 0
-        && val.get("width").unwrap().as_u64().unwrap() == 0
-        && val.get("height").unwrap().as_u64().unwrap() == 0
+fn is_scratchpad(node: &Node) -> bool {
+    node.node_type == NodeType::Workspace && node.name.as_deref() == Some("__i3_scratch")
 }
@@ -1,16 +1,32 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::{
+    collections::HashMap,
     env,
     error::Error,
     fmt,
     io::{self, prelude::*},
     os::unix::net::UnixStream,
     process,
-    str::FromStr,
 };
 
 // Copy heavily from https://github.com/tmerr/i3ipc-rs
 
+// https://i3wm.org/docs/ipc.html#_sending_messages_to_i3
+const RUN_COMMAND: u32 = 0;
+const GET_WORKSPACES: u32 = 1;
+const SUBSCRIBE: u32 = 2;
+const GET_OUTPUTS: u32 = 3;
+const GET_TREE: u32 = 4;
+const GET_MARKS: u32 = 5;
+const GET_BAR_CONFIG: u32 = 6;
+const GET_VERSION: u32 = 7;
+const GET_BINDING_MODES: u32 = 8;
+const GET_CONFIG: u32 = 9;
+const SEND_TICK: u32 = 10;
+const SYNC: u32 = 11;
+
 #[derive(Debug)]
 pub enum MessageError {
     /// Network error sending the message.
@@ -32,17 +48,21 @@ impl Error for MessageError {
 
 impl fmt::Display for MessageError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match *self {
-                MessageError::Send(_) => "Network error while sending message to i3",
-                MessageError::Receive(_) => "Network error while receiving message from i3",
-                MessageError::JsonCouldntParse(_) => {
-                    "Got a response from i3 but couldn't parse the JSON"
-                }
+        match *self {
+            MessageError::Send(ref e) => {
+                write!(f, "Network error while sending message to i3: {}", e)
+            }
+            MessageError::Receive(ref e) => {
+                write!(f, "Network error while receiving message from i3: {}", e)
             }
-        )
+            MessageError::JsonCouldntParse(ref e) => {
+                write!(
+                    f,
+                    "Got a response from i3 but couldn't parse the JSON: {}",
+                    e
+                )
+            }
+        }
     }
 }
 
@@ -76,7 +96,7 @@ impl I3Funcs for UnixStream {
                 "unexpected magic string: expected 'i3-ipc' but got {}",
                 magic_string
             );
-            return Err(io::Error::new(io::ErrorKind::Other, error_text));
+            return Err(io::Error::other(error_text));
         }
         let payload_len = self.read_u32::<LittleEndian>()?;
         let message_type = self.read_u32::<LittleEndian>()?;
@@ -96,7 +116,12 @@ impl I3Funcs for UnixStream {
         }
         let received = match self.receive_i3_message() {
             Ok((received_type, payload)) => {
-                assert_eq!(message_type, received_type);
+                if received_type != message_type {
+                    return Err(MessageError::Receive(io::Error::other(format!(
+                        "unexpected reply type: expected {} but got {}",
+                        message_type, received_type
+                    ))));
+                }
                 payload
             }
             Err(e) => {
@@ -119,6 +144,21 @@ pub struct CommandOutcome {
     pub error: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct RawCommandOutcome {
+    success: bool,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    parse_error: Option<bool>,
+}
+
+/// The reply to requests that only report whether they succeeded.
+#[derive(Deserialize)]
+struct SuccessReply {
+    success: bool,
+}
+
 /// The reply to the `command` request.
 #[derive(Debug)]
 pub struct Command {
@@ -126,6 +166,181 @@ pub struct Command {
     pub outcomes: Vec<CommandOutcome>,
 }
 
+/// A rectangle, as reported by i3 for containers, workspaces and outputs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn is_zero(&self) -> bool {
+        self.x == 0 && self.y == 0 && self.width == 0 && self.height == 0
+    }
+}
+
+/// The type of a node in the layout tree.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeType {
+    Root,
+    Output,
+    Con,
+    FloatingCon,
+    Workspace,
+    Dockarea,
+    /// A node type we don't know about yet.
+    #[serde(other)]
+    Unknown,
+}
+
+/// X11 properties of the window held by a leaf container.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WindowProperties {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_role: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transient_for: Option<u32>,
+    /// Properties we don't model, kept so the node round-trips.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A node of the layout tree, as returned by `get_tree`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Node {
+    pub id: u64,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub node_type: NodeType,
+    #[serde(default)]
+    pub layout: String,
+    #[serde(default)]
+    pub border: String,
+    #[serde(default = "default_border_width")]
+    pub current_border_width: i32,
+    #[serde(default)]
+    pub floating: Option<String>,
+    #[serde(default)]
+    pub percent: Option<f64>,
+    #[serde(default)]
+    pub rect: Rect,
+    #[serde(default)]
+    pub geometry: Rect,
+    #[serde(default)]
+    pub window: Option<u32>,
+    #[serde(default)]
+    pub window_properties: Option<WindowProperties>,
+    #[serde(default)]
+    pub marks: Vec<String>,
+    #[serde(default)]
+    pub focused: bool,
+    #[serde(default)]
+    pub focus: Vec<u64>,
+    #[serde(default)]
+    pub fullscreen_mode: u8,
+    #[serde(default)]
+    pub swallows: Vec<Map<String, Value>>,
+    #[serde(default)]
+    pub nodes: Vec<Node>,
+    #[serde(default)]
+    pub floating_nodes: Vec<Node>,
+    /// Fields we don't model, kept so the node round-trips.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+fn default_border_width() -> i32 {
+    -1
+}
+
+impl Node {
+    /// Iterates over the tiling children followed by the floating children.
+    pub fn children(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.iter().chain(self.floating_nodes.iter())
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.node_type == NodeType::Con && self.nodes.is_empty() && self.floating_nodes.is_empty()
+    }
+}
+
+/// A workspace, as returned by `get_workspaces`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Workspace {
+    pub id: u64,
+    pub num: i32,
+    pub name: String,
+    pub visible: bool,
+    pub focused: bool,
+    pub urgent: bool,
+    pub rect: Rect,
+    pub output: String,
+}
+
+/// An output, as returned by `get_outputs`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Output {
+    pub name: String,
+    pub active: bool,
+    #[serde(default)]
+    pub primary: bool,
+    pub current_workspace: Option<String>,
+    pub rect: Rect,
+}
+
+/// The configuration of a bar, as returned by `get_bar_config`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BarConfig {
+    pub id: String,
+    pub mode: String,
+    pub position: String,
+    #[serde(default)]
+    pub status_command: Option<String>,
+    #[serde(default)]
+    pub font: Option<String>,
+    #[serde(default)]
+    pub workspace_buttons: bool,
+    #[serde(default)]
+    pub binding_mode_indicator: bool,
+    #[serde(default)]
+    pub verbose: bool,
+    #[serde(default)]
+    pub colors: HashMap<String, String>,
+    /// Fields we don't model.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The reply to the `get_version` request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    pub human_readable: String,
+    #[serde(default)]
+    pub loaded_config_file_name: Option<String>,
+}
+
+/// The reply to the `get_config` request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct I3Config {
+    /// The contents of the loaded config file, with includes resolved.
+    pub config: String,
+}
+
 fn get_socket_path() -> io::Result<String> {
     if let Ok(sockpath) = env::var("I3SOCK") {
         return Ok(sockpath);
@@ -145,7 +360,7 @@ fn get_socket_path() -> io::Result<String> {
         } else {
             prefix.to_owned()
         };
-        let error = io::Error::new(io::ErrorKind::Other, error_text);
+        let error = io::Error::other(error_text);
         Err(error)
     }
 }
@@ -161,47 +376,154 @@ pub fn connect_i3() -> Result<UnixStream, io::Error> {
 }
 
 pub fn run_command(stream: &mut UnixStream, string: &str) -> Result<Command, MessageError> {
-    let j: serde_json::Value = stream.send_receive_i3_message(0, string)?;
-    let commands = j.as_array().unwrap();
-    let vec: Vec<_> = commands
-        .iter()
+    let outcomes: Vec<RawCommandOutcome> = stream.send_receive_i3_message(RUN_COMMAND, string)?;
+    let vec: Vec<_> = outcomes
+        .into_iter()
         .map(|c| CommandOutcome {
-            success: c.get("success").unwrap().as_bool().unwrap(),
-            error: match c.get("error") {
-                Some(val) => Some(val.as_str().unwrap().to_owned()),
-                None => c.get("parse_error").map(|_| "Parse error".to_owned()),
-            },
+            success: c.success,
+            error: c
+                .error
+                .or_else(|| c.parse_error.map(|_| "Parse error".to_owned())),
         })
         .collect();
 
     Ok(Command { outcomes: vec })
 }
 
-pub fn get_tree(stream: &mut UnixStream) -> Result<serde_json::Value, MessageError> {
-    let val: serde_json::Value = stream.send_receive_i3_message(4, "")?;
-    Ok(val)
+pub fn get_workspaces(stream: &mut UnixStream) -> Result<Vec<Workspace>, MessageError> {
+    stream.send_receive_i3_message(GET_WORKSPACES, "")
 }
 
-pub fn get_workspaces(stream: &mut UnixStream) -> Result<serde_json::Value, MessageError> {
-    let val: serde_json::Value = stream.send_receive_i3_message(1, "")?;
-    Ok(val)
+pub fn get_outputs(stream: &mut UnixStream) -> Result<Vec<Output>, MessageError> {
+    stream.send_receive_i3_message(GET_OUTPUTS, "")
 }
 
-/// Iterates over events from i3.
-///
-/// Each element may be `Err` or `Ok` (Err for an issue with the socket connection or data sent
-/// from i3).
+pub fn get_tree(stream: &mut UnixStream) -> Result<Node, MessageError> {
+    stream.send_receive_i3_message(GET_TREE, "")
+}
+
+pub fn get_marks(stream: &mut UnixStream) -> Result<Vec<String>, MessageError> {
+    stream.send_receive_i3_message(GET_MARKS, "")
+}
+
+/// Returns the ids of all configured bars.
+pub fn get_bar_ids(stream: &mut UnixStream) -> Result<Vec<String>, MessageError> {
+    stream.send_receive_i3_message(GET_BAR_CONFIG, "")
+}
+
+pub fn get_bar_config(stream: &mut UnixStream, id: &str) -> Result<BarConfig, MessageError> {
+    stream.send_receive_i3_message(GET_BAR_CONFIG, id)
+}
+
+pub fn get_version(stream: &mut UnixStream) -> Result<Version, MessageError> {
+    stream.send_receive_i3_message(GET_VERSION, "")
+}
+
+pub fn get_binding_modes(stream: &mut UnixStream) -> Result<Vec<String>, MessageError> {
+    stream.send_receive_i3_message(GET_BINDING_MODES, "")
+}
+
+pub fn get_config(stream: &mut UnixStream) -> Result<I3Config, MessageError> {
+    stream.send_receive_i3_message(GET_CONFIG, "")
+}
+
+/// Sends a tick event with the given payload to all clients subscribed to tick events.
+pub fn send_tick(stream: &mut UnixStream, payload: &str) -> Result<bool, MessageError> {
+    let reply: SuccessReply = stream.send_receive_i3_message(SEND_TICK, payload)?;
+    Ok(reply.success)
+}
+
+/// Asks i3 to send a `_NET_WM_SYNC` client message with `random` to `window` once it has
+/// processed all X11 events received so far.
+pub fn sync(stream: &mut UnixStream, random: u32, window: u32) -> Result<bool, MessageError> {
+    let payload = serde_json::json!({ "random": random, "window": window }).to_string();
+    let reply: SuccessReply = stream.send_receive_i3_message(SYNC, &payload)?;
+    Ok(reply.success)
+}
+
+/// An event type that can be subscribed to.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    Workspace,
+    Output,
+    Mode,
+    Window,
+    BarconfigUpdate,
+    Binding,
+    Shutdown,
+    Tick,
+}
+
+/// An event sent by i3, carrying its full payload.
 #[derive(Debug)]
-pub struct WindowEventIterator<'a> {
-    stream: &'a mut UnixStream,
+pub enum Event {
+    Workspace(Box<WorkspaceEventInfo>),
+    Output(OutputEventInfo),
+    Mode(ModeEventInfo),
+    Window(Box<WindowEventInfo>),
+    BarConfig(BarConfig),
+    Binding(BindingEventInfo),
+    Shutdown(ShutdownEventInfo),
+    Tick(TickEventInfo),
+}
+
+/// The kind of workspace change.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkspaceChange {
+    /// The workspace has received focus.
+    Focus,
+    /// The workspace has been created.
+    Init,
+    /// The workspace has become empty and is about to be destroyed.
+    Empty,
+    /// The workspace has become urgent or lost its urgent status.
+    Urgent,
+    /// The workspace has been renamed.
+    Rename,
+    /// The configuration file has been reloaded.
+    Reload,
+    /// A workspace has been restored from a layout.
+    Restored,
+    /// The workspace has been moved to another output.
+    Move,
+    /// A WorkspaceChange we don't support yet.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WorkspaceEventInfo {
+    /// Indicates the type of change
+    pub change: WorkspaceChange,
+    /// The workspace affected by the change.
+    pub current: Option<Node>,
+    /// The previously focused workspace, for `focus` changes.
+    pub old: Option<Node>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OutputEventInfo {
+    /// Currently always `unspecified`.
+    pub change: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModeEventInfo {
+    /// The name of the binding mode that is now active.
+    pub change: String,
+    #[serde(default)]
+    pub pango_markup: bool,
 }
 
 /// The kind of window change.
-#[derive(Debug, PartialEq)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
 pub enum WindowChange {
     /// The window has become managed by i3.
     New,
-    /// The window has closed>.
+    /// The window has closed.
     Close,
     /// The window has received input focus.
     Focus,
@@ -218,46 +540,99 @@ pub enum WindowChange {
     /// A mark has been added to or removed from the window.
     Mark,
     /// A WindowChange we don't support yet.
+    #[serde(other)]
     Unknown,
 }
 
-#[derive(Debug)]
+#[derive(Deserialize, Debug)]
 pub struct WindowEventInfo {
     /// Indicates the type of change
     pub change: WindowChange,
+    /// The window's parent container.
+    pub container: Node,
 }
 
-impl FromStr for WindowEventInfo {
-    type Err = serde_json::error::Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let val: serde_json::Value = serde_json::from_str(s)?;
-        Ok(WindowEventInfo {
-            change: match val.get("change").unwrap().as_str().unwrap() {
-                "new" => WindowChange::New,
-                "close" => WindowChange::Close,
-                "focus" => WindowChange::Focus,
-                "title" => WindowChange::Title,
-                "fullscreen_mode" => WindowChange::FullscreenMode,
-                "move" => WindowChange::Move,
-                "floating" => WindowChange::Floating,
-                "urgent" => WindowChange::Urgent,
-                "mark" => WindowChange::Mark,
-                _ => WindowChange::Unknown,
-            },
-        })
-    }
+/// A key or mouse binding from the i3 config.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Binding {
+    /// The i3 command that is configured to run for this binding.
+    pub command: String,
+    /// The modifiers that were configured with this binding.
+    #[serde(default)]
+    pub event_state_mask: Vec<String>,
+    /// The key code or mouse button of the binding, or 0 if a symbol is used.
+    pub input_code: i32,
+    /// The symbol of the binding, if any.
+    pub symbol: Option<String>,
+    /// Either `keyboard` or `mouse`.
+    pub input_type: String,
 }
 
-impl<'a> Iterator for WindowEventIterator<'a> {
-    type Item = Result<WindowEventInfo, MessageError>;
+#[derive(Deserialize, Debug)]
+pub struct BindingEventInfo {
+    /// Currently always `run`.
+    pub change: String,
+    pub binding: Binding,
+}
+
+/// The reason i3 is shutting down.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownChange {
+    Restart,
+    Exit,
+    /// A ShutdownChange we don't support yet.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ShutdownEventInfo {
+    /// Indicates the type of change
+    pub change: ShutdownChange,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TickEventInfo {
+    /// Whether this is the tick sent right after subscribing.
+    #[serde(default)]
+    pub first: bool,
+    /// The payload passed to `send_tick`.
+    #[serde(default)]
+    pub payload: String,
+}
+
+/// Iterates over events from i3.
+///
+/// Each element may be `Err` or `Ok` (Err for an issue with the socket connection or data sent
+/// from i3).
+#[derive(Debug)]
+pub struct EventIterator<'a> {
+    stream: &'a mut UnixStream,
+}
+
+impl<'a> Iterator for EventIterator<'a> {
+    type Item = Result<Event, MessageError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         /// the msgtype passed in should have its highest order bit stripped
         /// makes the i3 event
-        fn build_event(msgtype: u32, payload: &str) -> Result<WindowEventInfo, serde_json::Error> {
+        fn build_event(msgtype: u32, payload: &str) -> Result<Event, serde_json::Error> {
             Ok(match msgtype {
-                3 => WindowEventInfo::from_str(payload)?,
-                _ => unreachable!("received an event we aren't subscribed to!"),
+                0 => Event::Workspace(serde_json::from_str(payload)?),
+                1 => Event::Output(serde_json::from_str(payload)?),
+                2 => Event::Mode(serde_json::from_str(payload)?),
+                3 => Event::Window(serde_json::from_str(payload)?),
+                4 => Event::BarConfig(serde_json::from_str(payload)?),
+                5 => Event::Binding(serde_json::from_str(payload)?),
+                6 => Event::Shutdown(serde_json::from_str(payload)?),
+                7 => Event::Tick(serde_json::from_str(payload)?),
+                _ => {
+                    return Err(serde::de::Error::custom(format!(
+                        "received an unknown event type: {}",
+                        msgtype
+                    )))
+                }
             })
         }
 
@@ -276,14 +651,18 @@ impl<'a> Iterator for WindowEventIterator<'a> {
     }
 }
 
-pub fn subscribe_window_event(
-    stream: &mut UnixStream,
-) -> Result<Option<WindowEventIterator<'_>>, MessageError> {
-    let val: serde_json::Value = stream.send_receive_i3_message(2, "[\"window\"]")?;
-    let is_success = val.get("success").unwrap().as_bool().unwrap();
-    if !is_success {
+/// Subscribes to the given event types. Returns `None` if i3 refused the subscription.
+///
+/// The stream should be dedicated to events, as i3 will send them interleaved with any replies.
+pub fn subscribe<'a>(
+    stream: &'a mut UnixStream,
+    events: &[EventType],
+) -> Result<Option<EventIterator<'a>>, MessageError> {
+    let payload = serde_json::to_string(events).map_err(MessageError::JsonCouldntParse)?;
+    let reply: SuccessReply = stream.send_receive_i3_message(SUBSCRIBE, &payload)?;
+    if !reply.success {
         return Ok(None);
     }
 
-    Ok(Some(WindowEventIterator { stream }))
+    Ok(Some(EventIterator { stream }))
 }
@@ -1,5 +1,14 @@
 pub mod config;
+pub mod daemon;
+pub mod error;
 pub mod i3_tree;
 pub mod i3ipc;
 pub mod metadata;
+pub mod output;
 pub mod process;
+pub mod restore;
+pub mod session;
+pub mod shell;
+pub mod terminal;
+
+pub use error::{Error, Result};
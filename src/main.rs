@@ -1,138 +1,303 @@
 use chrono::{DateTime, Local};
-use directories::BaseDirs;
 use i3_revive::{
-    config::load_config,
-    i3_tree::{
-        find_workspaces, get_all_windows, remove_workspaces, restore_workspaces, save_workspaces,
-    },
-    i3ipc::{connect_i3, get_tree},
-    metadata::{remove_metadata, restore_metadata, save_metadata},
-    process::{remove_processes, restore_processes, save_processes},
+    config::{load_config, log_dir},
+    daemon::{run_daemon, DaemonOptions},
+    i3ipc::connect_i3,
+    output::get_output_infos,
+    restore::{plan_restore, restore_session, ReportFormat, RestoreOptions},
+    session::{backup, backup_and_clear, capture_session, list_sessions, Session, DEFAULT_SESSION},
+    Error, Result,
 };
-use std::{env, fs, io};
+use std::{env, fs, time::Duration};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+const USAGE: &str = "<command> [options]
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} <save|restore|rm>", args[0]);
-        std::process::exit(1);
-    }
+Commands:
+  save                      Save the current layout
+  restore                   Restore the saved layout
+  list                      List the saved sessions
+  rm                        Remove the saved layout
+  backups list              List the backups of a session
+  daemon                    Keep saving the layout as it changes
 
-    let command = &args[1];
+Options, only accepted by the commands they apply to:
+  --name <session>          Session to act on, for all but list (default: default)
+  --workspace <name>        Only save or restore this workspace, can be repeated
+  --backup <timestamp|N>    Restore a backup, as listed by `backups list`, instead
+  --dry-run                 Print what restore would do without doing it, not with
+                            --timeout, --kill-pending, --merge or --report
+  --timeout <seconds>       Wait this long for restored windows, 0 for no limit (default: 0)
+  --kill-pending            Close the placeholders still empty when restore times out,
+                            with --timeout
+  --merge                   Adopt running windows into matching placeholders instead of
+                            spawning their processes again
+  --report <table|json>     Print what happened to each window and process after restoring
+  --interval <seconds>      Save at least this often in daemon mode (default: 300)
+  --debounce <seconds>      Wait for changes to settle this long in daemon mode (default: 5)";
 
-    load_config();
-    match command.as_str() {
-        "save" => {
-            let mut stream = connect_i3().expect("Failed to connect to i3");
-            let root = get_tree(&mut stream).expect("Failed to get tree");
+struct Args {
+    command: String,
+    subcommand: Option<String>,
+    name: String,
+    workspaces: Vec<String>,
+    backup: Option<String>,
+    dry_run: bool,
+    restore_options: RestoreOptions,
+    report: Option<ReportFormat>,
+    daemon_options: DaemonOptions,
+}
 
-            let workspaces = find_workspaces(root);
-            let windows = get_all_windows(&workspaces);
+fn parse_args(args: &[String]) -> Option<Args> {
+    let mut args_iter = args.iter();
+    let mut parsed = Args {
+        command: args_iter.next()?.clone(),
+        subcommand: None,
+        name: DEFAULT_SESSION.to_string(),
+        workspaces: vec![],
+        backup: None,
+        dry_run: false,
+        restore_options: RestoreOptions::default(),
+        report: None,
+        daemon_options: DaemonOptions::default(),
+    };
+    // Options about actually restoring, which a dry run doesn't do
+    let mut restoring_options = false;
 
-            // Backup and clear existing data before saving new ones
-            if let Err(e) = backup_and_clear_data() {
-                eprintln!("Failed to create backup: {}", e);
-                std::process::exit(1);
-            }
+    while let Some(arg) = args_iter.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || inline_value.clone().or_else(|| args_iter.next().cloned());
 
-            save_workspaces(workspaces);
-            save_processes(windows);
-            save_metadata(&mut stream).expect("Failed to save metadata");
+        if flag.starts_with("--") && !accepts_flag(&parsed.command, flag) {
+            return None;
         }
-        "restore" => {
-            let mut stream = connect_i3().expect("Failed to connect to i3");
-            restore_workspaces(&mut stream);
-            restore_processes();
-            restore_metadata(&mut stream).expect("Failed to restore metadata");
+
+        match flag {
+            "--name" => parsed.name = value()?,
+            "--workspace" => parsed.workspaces.push(value()?),
+            "--backup" => parsed.backup = Some(value()?),
+            "--dry-run" if inline_value.is_none() => parsed.dry_run = true,
+            "--timeout" => {
+                restoring_options = true;
+                parsed.restore_options.timeout = match value()?.parse().ok()? {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                }
+            }
+            "--kill-pending" if inline_value.is_none() => {
+                restoring_options = true;
+                parsed.restore_options.kill_pending = true
+            }
+            "--merge" if inline_value.is_none() => {
+                restoring_options = true;
+                parsed.restore_options.merge = true
+            }
+            "--report" => {
+                restoring_options = true;
+                parsed.report = Some(match value()?.as_str() {
+                    "table" => ReportFormat::Table,
+                    "json" => ReportFormat::Json,
+                    _ => return None,
+                })
+            }
+            "--interval" => {
+                parsed.daemon_options.interval = Duration::from_secs(value()?.parse().ok()?)
+            }
+            "--debounce" => {
+                parsed.daemon_options.debounce = Duration::from_secs(value()?.parse().ok()?)
+            }
+            _ if !flag.starts_with("--") && parsed.subcommand.is_none() => {
+                parsed.subcommand = Some(flag.to_string())
+            }
+            _ => return None,
         }
-        "rm" => {
-            if let Err(e) = backup_and_clear_data() {
-                eprintln!("Failed to create backup: {}", e);
-                std::process::exit(1);
+    }
+
+    if parsed.dry_run && restoring_options {
+        return None;
+    }
+    if parsed.restore_options.kill_pending && parsed.restore_options.timeout.is_none() {
+        return None;
+    }
+
+    Some(parsed)
+}
+
+// Options are rejected by the commands that don't use them, rather than silently ignored
+fn accepts_flag(command: &str, flag: &str) -> bool {
+    match flag {
+        "--name" => matches!(command, "save" | "restore" | "rm" | "daemon" | "backups"),
+        "--workspace" => matches!(command, "save" | "restore"),
+        "--backup" | "--dry-run" | "--timeout" | "--kill-pending" | "--merge" | "--report" => {
+            command == "restore"
+        }
+        "--interval" | "--debounce" => command == "daemon",
+        _ => false,
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let Some(parsed_args) = parse_args(&args[1..]) else {
+        eprintln!("Usage: {} {}", args[0], USAGE);
+        std::process::exit(1);
+    };
+
+    let res = match (
+        parsed_args.command.as_str(),
+        parsed_args.subcommand.as_deref(),
+    ) {
+        ("save", None) => load_config()
+            .and_then(|_| save(&Session::open(&parsed_args.name)?, &parsed_args.workspaces)),
+        ("restore", None) => load_config().and_then(|_| {
+            let session = Session::open(&parsed_args.name)?;
+            match &parsed_args.backup {
+                Some(backup) => restore(&session.find_backup(backup)?.session, &parsed_args),
+                None => restore(&session, &parsed_args),
             }
-            println!("Successfully removed saved layouts and processes");
+        }),
+        ("list", None) => list(),
+        ("rm", None) => load_config().and_then(|_| {
+            let session = Session::open(&parsed_args.name)?;
+            backup_and_clear(&session)?;
+            clean_old_logs()?;
+            println!(
+                "Successfully removed saved layouts and processes of session {}",
+                session.name()
+            );
+            Ok(())
+        }),
+        ("daemon", None) => load_config().and_then(|_| {
+            clean_old_logs()?;
+            run_daemon(
+                &Session::open(&parsed_args.name)?,
+                &parsed_args.daemon_options,
+            )
+        }),
+        ("backups", Some("list")) => {
+            Session::open(&parsed_args.name).and_then(|session| list_backups(&session))
         }
         _ => {
-            eprintln!("Usage: {} <save|restore|rm>", args[0]);
+            eprintln!("Usage: {} {}", args[0], USAGE);
             std::process::exit(1);
         }
+    };
+
+    if let Err(e) = res {
+        eprintln!("Error: {}", e);
+        std::process::exit(match e {
+            Error::RestoreTimeout(_) => 2,
+            _ => 1,
+        });
     }
 }
 
-fn backup_and_clear_data() -> io::Result<()> {
-    let base_dirs = BaseDirs::new().expect("Failed to get base directories");
-    let mut source_dir = base_dirs.data_local_dir().to_path_buf();
-    source_dir.push("i3-revive");
+fn save(session: &Session, workspaces: &[String]) -> Result<()> {
+    let mut stream = connect_i3()?;
+    let mut data = capture_session(&mut stream, workspaces)?;
+
+    // Saving some workspaces leaves the other saved ones as they were
+    if !workspaces.is_empty() {
+        data = data.select_workspaces(workspaces)?;
+        if session.exists() {
+            let mut saved = session.load()?;
+            saved.merge_workspaces(data);
+            data = saved;
+        }
+    }
 
-    let timestamp = Local::now().format("%Y_%m_%d_%H_%M_%S_%3f").to_string();
-    let mut backups_dir = base_dirs.data_local_dir().to_path_buf();
-    backups_dir.push("i3-revive");
-    backups_dir.push("backups");
-    let backup_dir = backups_dir.join(timestamp);
+    // Backup existing data before it gets replaced by the new one
+    backup(session)?;
+    clean_old_logs()?;
 
-    fs::create_dir_all(&backup_dir)?;
+    session.save(&data)
+}
 
-    // Copy layouts directory
-    let mut layouts_source = source_dir.clone();
-    layouts_source.push("layouts");
-    if layouts_source.exists() {
-        let layouts_backup = backup_dir.join("layouts");
-        fs::create_dir_all(&layouts_backup)?;
+fn restore(session: &Session, args: &Args) -> Result<()> {
+    let data = session.load()?;
 
-        for entry in fs::read_dir(layouts_source)? {
-            let entry = entry?;
-            let dest_path = layouts_backup.join(entry.file_name());
-            fs::copy(entry.path(), dest_path)?;
-        }
+    if args.dry_run {
+        let data = if args.workspaces.is_empty() {
+            data
+        } else {
+            data.select_workspaces(&args.workspaces)?
+        };
+        // Without i3, the plan shows the saved outputs as they are
+        let current_outputs = connect_i3()
+            .map_err(Error::from)
+            .and_then(|mut stream| get_output_infos(&mut stream))
+            .ok();
+        print!("{}", plan_restore(&data, current_outputs.as_deref())?);
+        return Ok(());
     }
 
-    // Copy processes.json file
-    let mut processes_source = source_dir.clone();
-    processes_source.push("processes.json");
-    if processes_source.exists() {
-        let processes_backup = backup_dir.join("processes.json");
-        fs::copy(processes_source, processes_backup)?;
+    let options = RestoreOptions {
+        workspaces: args.workspaces.clone(),
+        ..args.restore_options.clone()
+    };
+    let mut stream = connect_i3()?;
+    let report = restore_session(&mut stream, &data, &options)?;
+    if let Some(format) = args.report {
+        print!("{}", report.format(format)?);
     }
 
-    // Copy metadata.json file
-    let mut metadata_source = source_dir.clone();
-    metadata_source.push("metadata.json");
-    if metadata_source.exists() {
-        let metadata_backup = backup_dir.join("metadata.json");
-        fs::copy(metadata_source, metadata_backup)?;
+    if report.timed_out {
+        return Err(Error::RestoreTimeout(report.unswallowed()));
     }
 
-    // Clean up old backups - keep only the 100 most recent
-    let mut entries = Vec::new();
+    Ok(())
+}
 
-    for entry in fs::read_dir(&backups_dir)? {
-        let entry = entry?;
-        if entry.file_type()?.is_dir() {
-            entries.push(entry.path());
-        }
+fn list() -> Result<()> {
+    for name in list_sessions()? {
+        println!("{}", name);
     }
 
-    // Sort by modification time (newest first)
-    entries.sort_by(|a, b| {
-        let a_time = fs::metadata(a).and_then(|m| m.modified()).unwrap();
-        let b_time = fs::metadata(b).and_then(|m| m.modified()).unwrap();
-        b_time.cmp(&a_time)
-    });
-
-    // Remove old backups beyond the limit
-    for old_backup in entries.iter().skip(1000) {
-        if let Err(e) = fs::remove_dir_all(old_backup) {
-            eprintln!(
-                "Warning: Failed to remove old backup {:?}: {}",
-                old_backup, e
-            );
-        }
+    Ok(())
+}
+
+fn list_backups(session: &Session) -> Result<()> {
+    let backups = session.backups()?;
+    if backups.is_empty() {
+        println!("Session {} has no backups", session.name());
+        return Ok(());
     }
 
+    println!(
+        "{:>4}  {:<23}  {:<6}  {:>10}  {:>9}",
+        "N", "TIMESTAMP", "SAVE", "WORKSPACES", "PROCESSES"
+    );
+    for (i, backup) in backups.iter().enumerate() {
+        // An unreadable backup is still listed, the others may be what is needed
+        let (workspaces, processes) = match backup.session.load() {
+            Ok(data) => (
+                data.workspaces.len().to_string(),
+                data.processes.len().to_string(),
+            ),
+            Err(e) => {
+                eprintln!("Warning: Cannot read backup {}: {}", backup.timestamp, e);
+                ("?".to_string(), "?".to_string())
+            }
+        };
+        println!(
+            "{:>4}  {:<23}  {:<6}  {:>10}  {:>9}",
+            i + 1,
+            backup.timestamp,
+            if backup.auto { "daemon" } else { "manual" },
+            workspaces,
+            processes
+        );
+    }
+
+    Ok(())
+}
+
+fn clean_old_logs() -> Result<()> {
     // Clean up old log files
-    let mut log_dir = base_dirs.state_dir().unwrap().to_path_buf();
-    log_dir.push("i3-revive");
+    let log_dir = log_dir()?;
     let thirty_days = chrono::Duration::days(30);
     for log_type in ["stdout", "stderr"].iter() {
         let mut log_type_dir = log_dir.clone();
@@ -157,10 +322,5 @@ fn backup_and_clear_data() -> io::Result<()> {
         }
     }
 
-    // Remove existing data
-    remove_workspaces();
-    remove_processes();
-    remove_metadata().expect("Failed to remove metadata");
-
     Ok(())
 }
@@ -1,119 +1,203 @@
 use std::cmp::Ordering;
-use std::fs::{self, File};
-use std::io::{self, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
 use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
 
-use directories::BaseDirs;
-use serde_json::{json, Value};
+use serde::{Deserialize, Serialize};
 
-use crate::i3_tree::{find_workspaces, get_all_windows};
+use crate::config::get_config;
+use crate::error::Result;
+use crate::i3_tree::{get_all_windows, quote, Window};
 use crate::i3ipc::{
-    connect_i3, get_tree, get_workspaces, run_command, subscribe_window_event, WindowChange,
+    connect_i3, get_tree, get_workspaces, run_command, subscribe, Event, EventType, MessageError,
+    WindowChange,
 };
-
-fn get_metadata_path() -> io::Result<std::path::PathBuf> {
-    let base_dirs = BaseDirs::new().expect("Failed to get base directories");
-    let mut path = base_dirs.data_local_dir().to_path_buf();
-    path.push("i3-revive");
-    fs::create_dir_all(&path)?;
-    path.push("metadata.json");
-    Ok(path)
+use crate::output::{get_output_infos, map_outputs, OutputInfo};
+
+/// State of the workspaces themselves, rather than of their contents.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Metadata {
+    /// Visible workspaces, with the focused one last.
+    pub visible_workspaces: Vec<String>,
+    /// The output each workspace was on, by workspace name.
+    #[serde(default)]
+    pub workspace_outputs: BTreeMap<String, String>,
 }
 
-pub fn save_metadata(stream: &mut UnixStream) -> io::Result<()> {
-    let workspaces = get_workspaces(stream).expect("Failed to get workspaces");
+pub fn save_metadata(stream: &mut UnixStream) -> Result<Metadata> {
+    let workspaces = get_workspaces(stream)?;
     let mut visible_workspaces = workspaces
-        .as_array()
-        .unwrap()
         .iter()
-        .filter(|ws| {
-            ws.get("visible")
-                .and_then(|visible| visible.as_bool())
-                .unwrap_or(false)
-        })
+        .filter(|ws| ws.visible)
         .collect::<Vec<_>>();
 
     // We want the focused workspace to be last so it will be focused when restoring
     visible_workspaces.sort_by(|a, _| {
-        if a.get("focused")
-            .and_then(|focused| focused.as_bool())
-            .unwrap_or(false)
-        {
+        if a.focused {
             Ordering::Greater
         } else {
             Ordering::Less
         }
     });
 
-    let visible_workspace_names = visible_workspaces
-        .iter()
-        .map(|ws| ws.get("name").unwrap().as_str().unwrap())
-        .collect::<Vec<_>>();
+    Ok(Metadata {
+        visible_workspaces: visible_workspaces
+            .iter()
+            .map(|ws| ws.name.clone())
+            .collect(),
+        workspace_outputs: workspaces
+            .iter()
+            .map(|ws| (ws.name.clone(), ws.output.clone()))
+            .collect(),
+    })
+}
+
+/// Decides which of the `current` outputs the workspaces of each saved output go to, by saved
+/// output name.
+///
+/// `saved_outputs` identifies the monitors the workspaces were on, so a workspace follows its
+/// monitor even if it is now plugged into another connector. Outputs that are gone are remapped
+/// as configured by `output_mappings` and `output_fallback`.
+pub fn map_workspace_outputs(
+    metadata: &Metadata,
+    saved_outputs: &[OutputInfo],
+    current: &[OutputInfo],
+) -> Result<HashMap<String, String>> {
+    let config = get_config()?;
+
+    // Sessions saved before outputs were recorded only know the connector names
+    let mut saved_outputs = saved_outputs.to_vec();
+    for name in metadata.workspace_outputs.values() {
+        if !saved_outputs.iter().any(|output| &output.name == name) {
+            saved_outputs.push(OutputInfo {
+                name: name.clone(),
+                ..Default::default()
+            });
+        }
+    }
+
+    Ok(map_outputs(
+        &saved_outputs,
+        current,
+        &config.output_mappings,
+        config.output_fallback,
+    ))
+}
+
+/// Moves the workspaces back to the outputs they were saved on, as decided by
+/// [`map_workspace_outputs`].
+pub fn restore_workspace_outputs(
+    stream: &mut UnixStream,
+    metadata: &Metadata,
+    saved_outputs: &[OutputInfo],
+) -> Result<()> {
+    let current = get_output_infos(stream)?;
+    let output_map = map_workspace_outputs(metadata, saved_outputs, &current)?;
+
+    for ws in get_workspaces(stream)? {
+        let Some(saved_name) = metadata.workspace_outputs.get(&ws.name) else {
+            continue;
+        };
+
+        let Some(target) = output_map.get(saved_name) else {
+            eprintln!(
+                "Warning: Output {} of workspace {} is not connected, leaving it on {}",
+                saved_name, ws.name, ws.output
+            );
+            continue;
+        };
+        if target == &ws.output {
+            continue;
+        }
+
+        // move workspace only acts on the focused workspace
+        run_command(stream, format!("workspace {}", quote(&ws.name)).as_str())?;
+        run_command(
+            stream,
+            format!("move workspace to output {}", quote(target)).as_str(),
+        )?;
+    }
 
-    let path = get_metadata_path()?;
-    let mut file = File::create(path)?;
-    let metadata = json!({
-        "visible_workspaces": visible_workspace_names
-    });
-    file.write_all(serde_json::to_string_pretty(&metadata)?.as_bytes())?;
     Ok(())
 }
 
-pub fn restore_metadata(stream: &mut UnixStream) -> io::Result<()> {
-    let path = get_metadata_path()?;
-    if !path.exists() {
-        eprintln!("Metadata file is missing");
-        std::process::exit(1);
+/// Blocks until every placeholder has swallowed a window, or until the timeout expires.
+///
+/// Returns the placeholders still waiting for a window, which is empty unless it timed out.
+pub fn wait_for_placeholders(
+    stream: &mut UnixStream,
+    timeout: Option<Duration>,
+) -> Result<Vec<Window>> {
+    // The whole tree is searched, as placeholders may be in the scratchpad too
+    let get_placeholders = |stream: &mut UnixStream| -> Result<Vec<Window>> {
+        let root = get_tree(stream)?;
+        Ok(get_all_windows(std::slice::from_ref(&root))
+            .into_iter()
+            .filter(|win| win.is_placeholder)
+            .collect())
+    };
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut event_stream = connect_i3()?;
+    // Shares the socket, so the read timeout applies to the event stream borrowed below
+    let timeout_handle = event_stream.try_clone()?;
+    let mut event_it = subscribe(&mut event_stream, &[EventType::Window])?.ok_or_else(|| {
+        MessageError::Receive(io::Error::other("i3 refused to subscribe to window events"))
+    })?;
+
+    // Only checked once subscribed, so a window swallowed in between still sends an event
+    let placeholders = get_placeholders(stream)?;
+    if placeholders.is_empty() {
+        return Ok(placeholders);
     }
 
-    let root = get_tree(stream).expect("Failed to get tree");
-    let tree_workspaces = find_workspaces(root);
-    let windows = get_all_windows(&tree_workspaces);
-    if windows.iter().any(|win| win.is_placeholder) {
-        let mut event_stream = connect_i3().expect("Failed to connect to i3");
-        let event_it = subscribe_window_event(&mut event_stream).unwrap().unwrap();
-        for window_info in event_it.flatten() {
-            if window_info.change == WindowChange::New || window_info.change == WindowChange::Close
+    loop {
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return get_placeholders(stream);
+            }
+            timeout_handle.set_read_timeout(Some(remaining))?;
+        }
+
+        let window_info = match event_it.next() {
+            Some(Ok(Event::Window(window_info))) => window_info,
+            Some(Err(MessageError::Receive(e)))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
             {
-                let root = get_tree(stream).expect("Failed to get tree");
-                let tree_workspaces = find_workspaces(root);
-                let windows = get_all_windows(&tree_workspaces);
-
-                // no placeholders means all windows are revived, we're now safe to revive visible workspaces
-                if windows.iter().all(|win| !win.is_placeholder) {
-                    break;
-                }
+                return get_placeholders(stream);
+            }
+            Some(Err(e)) => return Err(e.into()),
+            Some(Ok(_)) => continue,
+            None => return get_placeholders(stream),
+        };
+
+        if window_info.change == WindowChange::New || window_info.change == WindowChange::Close {
+            // no placeholders means all windows are revived
+            let placeholders = get_placeholders(stream)?;
+            if placeholders.is_empty() {
+                return Ok(placeholders);
             }
         }
     }
+}
 
-    let workspaces = get_workspaces(stream).expect("Failed to get workspaces");
-    let json_content = fs::read_to_string(&path).expect("Failed to read metadata.json file");
-    let metadata: Value =
-        serde_json::from_str(&json_content).expect("Failed to deserialize metadata.json");
-
-    let restoring_workspaces = metadata
-        .get("visible_workspaces")
-        .unwrap()
-        .as_array()
-        .unwrap();
+/// Brings back the visible workspaces, which is only safe once every placeholder is filled.
+pub fn restore_metadata(stream: &mut UnixStream, metadata: &Metadata) -> Result<()> {
+    let workspaces = get_workspaces(stream)?;
     let focused_workspace_name = workspaces
-        .as_array()
-        .unwrap()
         .iter()
-        .find(|ws| {
-            ws.get("focused")
-                .and_then(|focused| focused.as_bool())
-                .unwrap_or(false)
-        })
-        .map(|ws| ws.get("name").unwrap().as_str().unwrap());
+        .find(|ws| ws.focused)
+        .map(|ws| ws.name.as_str());
 
     let mut first_ws = true;
-    for ws in restoring_workspaces {
-        let ws_name = ws.as_str().unwrap();
-
+    for ws_name in &metadata.visible_workspaces {
         if !first_ws || focused_workspace_name.is_none_or(|name| name != ws_name) {
-            run_command(stream, format!("workspace {}", ws_name).as_str()).unwrap();
+            run_command(stream, format!("workspace {}", quote(ws_name)).as_str())?;
         }
 
         first_ws = false;
@@ -121,11 +205,3 @@ pub fn restore_metadata(stream: &mut UnixStream) -> io::Result<()> {
 
     Ok(())
 }
-
-pub fn remove_metadata() -> io::Result<()> {
-    let path = get_metadata_path()?;
-    if path.exists() {
-        fs::remove_file(path)?;
-    }
-    Ok(())
-}
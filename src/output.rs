@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+use std::os::unix::net::UnixStream;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use xcb::{randr, x};
+
+use crate::config::{OutputFallback, OutputMapping};
+use crate::error::Result;
+use crate::i3ipc::{get_outputs, Rect};
+
+/// An active output, identified by its connector name and, when its EDID can be read, by the
+/// monitor plugged into it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct OutputInfo {
+    /// The connector name, e.g. `DP-1`.
+    pub name: String,
+    #[serde(default)]
+    pub primary: bool,
+    #[serde(default)]
+    pub rect: Rect,
+    /// The three-letter PNP manufacturer id from the EDID, e.g. `DEL`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub make: Option<String>,
+    /// The monitor name from the EDID, or its product code when it has none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+}
+
+impl OutputInfo {
+    // Connector names change between docks and drivers, the monitor doesn't
+    fn same_monitor(&self, other: &OutputInfo) -> bool {
+        self.make.is_some()
+            && self.model.is_some()
+            && self.make == other.make
+            && self.model == other.model
+            && self.serial == other.serial
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Edid {
+    make: Option<String>,
+    model: Option<String>,
+    serial: Option<String>,
+}
+
+/// Returns the active outputs, with the monitor identity of each one whose EDID is available.
+pub fn get_output_infos(stream: &mut UnixStream) -> Result<Vec<OutputInfo>> {
+    let mut edids = match read_edids() {
+        Ok(edids) => edids,
+        Err(e) => {
+            eprintln!("Warning: Cannot read the EDID of outputs: {}", e);
+            HashMap::new()
+        }
+    };
+
+    Ok(get_outputs(stream)?
+        .into_iter()
+        .filter(|output| output.active)
+        .map(|output| {
+            let edid = edids.remove(&output.name).unwrap_or_default();
+            OutputInfo {
+                name: output.name,
+                primary: output.primary,
+                rect: output.rect,
+                make: edid.make,
+                model: edid.model,
+                serial: edid.serial,
+            }
+        })
+        .collect())
+}
+
+/// Decides which current output the workspaces of each saved output are restored to, by saved
+/// output name.
+///
+/// The first mapping matching a saved output wins, as long as its target is connected. Otherwise
+/// the output showing the same monitor, or with the same connector name, is used. Saved outputs
+/// matching none of them are handled by the fallback, and left out with [`OutputFallback::None`].
+pub fn map_outputs(
+    saved: &[OutputInfo],
+    current: &[OutputInfo],
+    mappings: &[OutputMapping],
+    fallback: OutputFallback,
+) -> HashMap<String, String> {
+    let mut current = current.iter().collect::<Vec<_>>();
+    current.sort_by_key(|output| (output.rect.x, output.rect.y));
+    let primary = current
+        .iter()
+        .find(|output| output.primary)
+        .or(current.first())
+        .copied();
+
+    let mut res = HashMap::new();
+    let mut next_round_robin = 0;
+    for saved in saved {
+        let target = mappings
+            .iter()
+            .filter(|mapping| mapping_matches(mapping, saved))
+            .find_map(|mapping| match mapping.output.as_str() {
+                "primary" => primary,
+                name => current.iter().find(|output| output.name == name).copied(),
+            })
+            .or_else(|| {
+                current
+                    .iter()
+                    .find(|output| output.same_monitor(saved))
+                    .copied()
+            })
+            .or_else(|| {
+                current
+                    .iter()
+                    .find(|output| output.name == saved.name)
+                    .copied()
+            })
+            .or_else(|| match fallback {
+                OutputFallback::None => None,
+                OutputFallback::Primary => primary,
+                OutputFallback::RoundRobin if current.is_empty() => None,
+                OutputFallback::RoundRobin => {
+                    next_round_robin += 1;
+                    Some(current[(next_round_robin - 1) % current.len()])
+                }
+            });
+
+        if let Some(target) = target {
+            res.insert(saved.name.clone(), target.name.clone());
+        }
+    }
+
+    res
+}
+
+// mapping regexes are validated when loading the config
+fn mapping_matches(mapping: &OutputMapping, saved: &OutputInfo) -> bool {
+    mapping
+        .name
+        .as_ref()
+        .is_none_or(|re| Regex::new(re).unwrap().is_match(&saved.name))
+        && mapping
+            .resolution
+            .is_none_or(|[width, height]| saved.rect.width == width && saved.rect.height == height)
+        && mapping
+            .position
+            .is_none_or(|[x, y]| saved.rect.x == x && saved.rect.y == y)
+}
+
+// The EDID of every connected output, by connector name
+fn read_edids() -> Result<HashMap<String, Edid>> {
+    let (conn, screen_num) =
+        xcb::Connection::connect_with_extensions(None, &[xcb::Extension::RandR], &[])?;
+    let root = conn
+        .get_setup()
+        .roots()
+        .nth(screen_num as usize)
+        .map(|screen| screen.root())
+        .ok_or(xcb::ConnError::ClosedInvalidScreen)?;
+
+    let edid_atom = conn.send_request(&x::InternAtom {
+        only_if_exists: true,
+        name: b"EDID",
+    });
+    let edid_atom = conn.wait_for_reply(edid_atom)?.atom();
+    if edid_atom == x::ATOM_NONE {
+        return Ok(HashMap::new());
+    }
+
+    let resources = conn.send_request(&randr::GetScreenResourcesCurrent { window: root });
+    let resources = conn.wait_for_reply(resources)?;
+
+    let mut edids = HashMap::new();
+    for &output in resources.outputs() {
+        let info = conn.send_request(&randr::GetOutputInfo {
+            output,
+            config_timestamp: resources.config_timestamp(),
+        });
+        let info = conn.wait_for_reply(info)?;
+        if info.connection() != randr::Connection::Connected {
+            continue;
+        }
+
+        let property = conn.send_request(&randr::GetOutputProperty {
+            output,
+            property: edid_atom,
+            r#type: x::ATOM_ANY,
+            long_offset: 0,
+            // in 32-bit units, enough for the base block
+            long_length: 128,
+            delete: false,
+            pending: false,
+        });
+        let property = conn.wait_for_reply(property)?;
+
+        if let Some(edid) = parse_edid(property.data::<u8>()) {
+            edids.insert(String::from_utf8_lossy(info.name()).into_owned(), edid);
+        }
+    }
+
+    Ok(edids)
+}
+
+// https://en.wikipedia.org/wiki/Extended_Display_Identification_Data#EDID_1.4_data_format
+fn parse_edid(edid: &[u8]) -> Option<Edid> {
+    const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+    if edid.len() < 128 || edid[..8] != HEADER {
+        return None;
+    }
+
+    // Three 5-bit letters, 1 being 'A'
+    let manufacturer = u16::from_be_bytes([edid[8], edid[9]]);
+    let make = [10, 5, 0]
+        .iter()
+        .map(|shift| match (manufacturer >> shift) & 0x1f {
+            letter @ 1..=26 => Some((b'A' + letter as u8 - 1) as char),
+            _ => None,
+        })
+        .collect::<Option<String>>();
+    let product_code = u16::from_le_bytes([edid[10], edid[11]]);
+    let serial_number = u32::from_le_bytes([edid[12], edid[13], edid[14], edid[15]]);
+
+    let mut monitor_name = None;
+    let mut serial_string = None;
+    for descriptor in edid[54..126].chunks(18) {
+        if descriptor[..3] != [0, 0, 0] {
+            continue;
+        }
+
+        // Text is terminated by a line feed and padded with spaces
+        let text = String::from_utf8_lossy(&descriptor[5..])
+            .split('\n')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        match descriptor[3] {
+            0xfc if !text.is_empty() => monitor_name = Some(text),
+            0xff if !text.is_empty() => serial_string = Some(text),
+            _ => {}
+        }
+    }
+
+    Some(Edid {
+        make,
+        model: monitor_name.or_else(|| Some(format!("{:04x}", product_code))),
+        serial: serial_string.or_else(|| (serial_number != 0).then(|| serial_number.to_string())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(name: &str, x: i32, width: u32, monitor: Option<[&str; 3]>) -> OutputInfo {
+        let [make, model, serial] = monitor.map_or([None; 3], |monitor| monitor.map(Some));
+        OutputInfo {
+            name: name.to_string(),
+            rect: Rect {
+                x,
+                y: 0,
+                width,
+                height: if width == 2560 { 1440 } else { 1080 },
+            },
+            make: make.map(String::from),
+            model: model.map(String::from),
+            serial: serial.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    fn mapping(
+        name: Option<&str>,
+        resolution: Option<[u32; 2]>,
+        position: Option<[i32; 2]>,
+        output: &str,
+    ) -> OutputMapping {
+        OutputMapping {
+            name: name.map(String::from),
+            resolution,
+            position,
+            output: output.to_string(),
+        }
+    }
+
+    const DELL: [&str; 3] = ["DEL", "DELL U2720Q", "AAA"];
+
+    #[test]
+    fn map_outputs_cases() {
+        let saved = [
+            output("DP-1", 0, 2560, Some(DELL)),
+            output("eDP-1", 2560, 1920, None),
+            output("HDMI-2", 4480, 1920, None),
+            output("DP-5", 6400, 2560, None),
+        ];
+        // Listed right to left, as they are sorted by position
+        let current = vec![
+            OutputInfo {
+                primary: true,
+                ..output("eDP-1", 2560, 1920, None)
+            },
+            output("DP-3", 0, 2560, Some(DELL)),
+        ];
+
+        struct Case {
+            name: &'static str,
+            current: Vec<OutputInfo>,
+            mappings: Vec<OutputMapping>,
+            fallback: OutputFallback,
+            expected: &'static [(&'static str, &'static str)],
+        }
+        let cases = [
+            Case {
+                name: "monitor and name",
+                current: current.clone(),
+                mappings: vec![],
+                fallback: OutputFallback::None,
+                expected: &[("DP-1", "DP-3"), ("eDP-1", "eDP-1")],
+            },
+            Case {
+                name: "primary fallback",
+                current: current.clone(),
+                mappings: vec![],
+                fallback: OutputFallback::Primary,
+                expected: &[
+                    ("DP-1", "DP-3"),
+                    ("eDP-1", "eDP-1"),
+                    ("HDMI-2", "eDP-1"),
+                    ("DP-5", "eDP-1"),
+                ],
+            },
+            Case {
+                name: "primary fallback without a primary output",
+                current: vec![
+                    output("eDP-1", 2560, 1920, None),
+                    output("DP-3", 0, 2560, Some(DELL)),
+                ],
+                mappings: vec![],
+                fallback: OutputFallback::Primary,
+                expected: &[
+                    ("DP-1", "DP-3"),
+                    ("eDP-1", "eDP-1"),
+                    ("HDMI-2", "DP-3"),
+                    ("DP-5", "DP-3"),
+                ],
+            },
+            Case {
+                name: "round robin fallback, left to right",
+                current: current.clone(),
+                mappings: vec![],
+                fallback: OutputFallback::RoundRobin,
+                expected: &[
+                    ("DP-1", "DP-3"),
+                    ("eDP-1", "eDP-1"),
+                    ("HDMI-2", "DP-3"),
+                    ("DP-5", "eDP-1"),
+                ],
+            },
+            Case {
+                name: "round robin fallback without outputs",
+                current: vec![],
+                mappings: vec![],
+                fallback: OutputFallback::RoundRobin,
+                expected: &[],
+            },
+            Case {
+                name: "mappings come first, in order",
+                current: current.clone(),
+                mappings: vec![
+                    mapping(None, None, Some([4480, 0]), "eDP-1"),
+                    mapping(None, Some([1920, 1080]), None, "DP-3"),
+                    mapping(Some("^DP-"), None, None, "primary"),
+                ],
+                fallback: OutputFallback::None,
+                expected: &[
+                    ("DP-1", "eDP-1"),
+                    ("eDP-1", "DP-3"),
+                    ("HDMI-2", "eDP-1"),
+                    ("DP-5", "eDP-1"),
+                ],
+            },
+            Case {
+                name: "mappings to disconnected outputs are skipped",
+                current: current.clone(),
+                mappings: vec![
+                    mapping(Some("^DP-"), None, None, "HDMI-9"),
+                    mapping(Some("^DP-5$"), None, None, "DP-3"),
+                ],
+                fallback: OutputFallback::None,
+                expected: &[("DP-1", "DP-3"), ("eDP-1", "eDP-1"), ("DP-5", "DP-3")],
+            },
+            Case {
+                name: "same monitor over same name",
+                current: vec![
+                    output("DP-1", 0, 1920, None),
+                    output("DP-3", 1920, 2560, Some(DELL)),
+                ],
+                mappings: vec![],
+                fallback: OutputFallback::None,
+                expected: &[("DP-1", "DP-3")],
+            },
+        ];
+
+        for case in cases {
+            let expected = case
+                .expected
+                .iter()
+                .map(|(saved, current)| (saved.to_string(), current.to_string()))
+                .collect::<HashMap<_, _>>();
+            assert_eq!(
+                map_outputs(&saved, &case.current, &case.mappings, case.fallback),
+                expected,
+                "{}",
+                case.name
+            );
+        }
+    }
+
+    // A base EDID block made by `manufacturer`, with text descriptors
+    fn edid(
+        manufacturer: &str,
+        product_code: u16,
+        serial_number: u32,
+        texts: &[(u8, &str)],
+    ) -> Vec<u8> {
+        let mut edid = vec![0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+        let letters = manufacturer
+            .bytes()
+            .fold(0u16, |id, letter| id << 5 | (letter - b'A' + 1) as u16);
+        edid.extend(letters.to_be_bytes());
+        edid.extend(product_code.to_le_bytes());
+        edid.extend(serial_number.to_le_bytes());
+        edid.resize(54, 0);
+        for (tag, text) in texts {
+            let mut descriptor = vec![0, 0, 0, *tag, 0];
+            descriptor.extend(text.bytes());
+            descriptor.push(b'\n');
+            descriptor.resize(18, b' ');
+            edid.extend(descriptor);
+        }
+        edid.resize(128, 0);
+        edid
+    }
+
+    #[test]
+    fn parse_edid_cases() {
+        let edid_of = |make: Option<&str>, model: &str, serial: Option<&str>| Edid {
+            make: make.map(String::from),
+            model: Some(model.to_string()),
+            serial: serial.map(String::from),
+        };
+        let cases = [
+            (
+                "descriptors",
+                edid(
+                    "DEL",
+                    0xa0f1,
+                    12345,
+                    &[(0xfc, "DELL U2720Q"), (0xff, "ABC123")],
+                ),
+                Some(edid_of(Some("DEL"), "DELL U2720Q", Some("ABC123"))),
+            ),
+            (
+                "product code and serial number",
+                edid("GSM", 0xa0f1, 12345, &[]),
+                Some(edid_of(Some("GSM"), "a0f1", Some("12345"))),
+            ),
+            (
+                "empty descriptors and no serial number",
+                edid("AUO", 0x1, 0, &[(0xfc, ""), (0xff, "")]),
+                Some(edid_of(Some("AUO"), "0001", None)),
+            ),
+            (
+                "invalid manufacturer",
+                {
+                    let mut edid = edid("DEL", 0x1, 0, &[]);
+                    edid[8..10].copy_from_slice(&[0, 0]);
+                    edid
+                },
+                Some(edid_of(None, "0001", None)),
+            ),
+            ("invalid header", vec![0; 128], None),
+            ("truncated", edid("DEL", 0x1, 0, &[])[..100].to_vec(), None),
+        ];
+
+        for (name, edid, expected) in cases {
+            assert_eq!(parse_edid(&edid), expected, "{}", name);
+        }
+    }
+}
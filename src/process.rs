@@ -1,25 +1,69 @@
-use crate::config::{TerminalCommandMapping, WindowCommandMapping, CONFIG};
-use crate::i3_tree;
+use crate::config::{
+    get_config, log_dir, TerminalBackend, TerminalCommandMapping, WindowCommandMapping,
+};
+use crate::error::{Error, Result};
+use crate::i3_tree::{self, get_swallow_criteria};
+use crate::shell::{Shell, REVIVE_MARK};
+use crate::terminal::{self, capture_tabs, get_multiplexer_cmd, ForegroundProcess, TerminalTab};
 use chrono::Local;
-use directories::BaseDirs;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use shlex::{split, try_join};
-use std::collections::HashSet;
-use std::error::Error;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
 use std::os::unix::process::CommandExt;
-use std::process::{Command, Stdio};
-use std::{fs, io, os::unix::fs::PermissionsExt, path::Path};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
 use xcb::{x, XidNew};
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Process {
-    command: Vec<String>,
-    working_directory: String,
+/// A process to spawn when restoring, so that it opens a window to be swallowed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Process {
+    pub command: Vec<String>,
+    pub working_directory: String,
+    /// Environment variables set when spawning the process, on top of the current ones.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environment: BTreeMap<String, String>,
+    /// The workspace of the window the process was saved for, or `None` for the scratchpad.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workspace: Option<String>,
+    /// The window the process was saved for, which it is expected to open again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<ProcessWindow>,
 }
 
-fn get_pid(window: u32) -> Result<u32, Box<dyn Error>> {
+/// The window of a saved process, as it was when saved.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProcessWindow {
+    /// The X window id, which is not valid anymore once restored.
+    pub id: u32,
+    pub class: Option<String>,
+    /// The criteria of the placeholder that will swallow the window.
+    pub swallows: Map<String, Value>,
+}
+
+#[cfg(test)]
+impl Process {
+    /// A process saved for a window of `class` on `workspace`, or without its window when `class`
+    /// is `None`, as in sessions saved before processes were linked to their window.
+    pub(crate) fn for_window(command: &str, workspace: Option<&str>, class: Option<&str>) -> Self {
+        Process {
+            command: vec![command.to_string()],
+            working_directory: "/".to_string(),
+            environment: BTreeMap::new(),
+            workspace: workspace.map(String::from),
+            window: class.map(|class| ProcessWindow {
+                id: 1,
+                class: Some(class.to_string()),
+                swallows: Map::from_iter([("class".to_string(), format!("^{}$", class).into())]),
+            }),
+        }
+    }
+}
+
+fn get_pid(window: u32) -> Result<u32> {
     let (conn, _) = xcb::Connection::connect(None)?;
 
     let wm_pid = conn.send_request(&x::InternAtom {
@@ -27,11 +71,13 @@ fn get_pid(window: u32) -> Result<u32, Box<dyn Error>> {
         name: "_NET_WM_PID".as_bytes(),
     });
     let wm_pid = conn.wait_for_reply(wm_pid)?.atom();
-    assert!(wm_pid != x::ATOM_NONE, "_NET_WM_PID is not supported");
+    if wm_pid == x::ATOM_NONE {
+        return Err(Error::Proc("_NET_WM_PID is not supported".into()));
+    }
 
     let cookie = conn.send_request(&x::GetProperty {
         delete: false,
-        window: unsafe { XidNew::new(window) },
+        window: XidNew::new(window),
         property: wm_pid,
         r#type: x::ATOM_CARDINAL,
         long_offset: 0,
@@ -40,7 +86,7 @@ fn get_pid(window: u32) -> Result<u32, Box<dyn Error>> {
     let reply = conn.wait_for_reply(cookie)?;
     let value = reply.value::<u32>();
     if value.is_empty() {
-        Err("Window has no pid".into())
+        Err(Error::Proc("Window has no pid".into()))
     } else {
         Ok(value[0])
     }
@@ -57,11 +103,8 @@ fn is_executable(path: &Path) -> bool {
 }
 
 // https://github.com/giampaolo/psutil/issues/1179
-fn get_process_cmd(pid: u32) -> Result<Vec<String>, String> {
-    let raw_cmd = match fs::read_to_string(format!("/proc/{}/cmdline", pid)) {
-        Ok(cmd) => cmd,
-        Err(err) => return Err(err.to_string()),
-    };
+pub(crate) fn get_process_cmd(pid: u32) -> Result<Vec<String>> {
+    let raw_cmd = fs::read_to_string(format!("/proc/{}/cmdline", pid))?;
 
     let cmd_parts = raw_cmd
         .trim_matches('\0')
@@ -74,7 +117,7 @@ fn get_process_cmd(pid: u32) -> Result<Vec<String>, String> {
     }
 
     // Process may use space (" ") as a separator
-    let raw_cmd = cmd_parts.first().unwrap();
+    let raw_cmd = &cmd_parts[0];
 
     // Process's executable name may have space (" "), so we just have to try
     // every combinations until finding an existing executable
@@ -97,368 +140,641 @@ fn get_process_cmd(pid: u32) -> Result<Vec<String>, String> {
     Ok(cmd_parts)
 }
 
-fn get_terminal_process_cmd(
+pub(crate) fn get_process_env(pid: u32) -> Result<HashMap<String, String>> {
+    Ok(fs::read(format!("/proc/{}/environ", pid))?
+        .split(|&byte| byte == 0)
+        .filter_map(|var| {
+            let var = String::from_utf8_lossy(var);
+            let (name, value) = var.split_once('=')?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect())
+}
+
+// Variables telling that a process runs in a virtualenv, conda, nix-shell or direnv environment
+const ENVIRONMENT_MARKERS: [&str; 4] =
+    ["VIRTUAL_ENV", "CONDA_PREFIX", "IN_NIX_SHELL", "DIRENV_DIR"];
+
+// Variables making up these environments, saved along with their markers
+const ENVIRONMENT_VARIABLES: [&str; 12] = [
+    "PATH",
+    "VIRTUAL_ENV",
+    "VIRTUAL_ENV_PROMPT",
+    "CONDA_PREFIX",
+    "CONDA_DEFAULT_ENV",
+    "CONDA_SHLVL",
+    "CONDA_PYTHON_EXE",
+    "IN_NIX_SHELL",
+    "DIRENV_DIR",
+    "DIRENV_FILE",
+    "DIRENV_DIFF",
+    "DIRENV_WATCHES",
+];
+
+// The variables of a process worth restoring, as configured
+fn get_saved_environment(pid: u32) -> Result<BTreeMap<String, String>> {
+    let config = get_config()?;
+    let env = get_process_env(pid)?;
+    let detected = config.detect_environments
+        && ENVIRONMENT_MARKERS
+            .iter()
+            .any(|&name| env.contains_key(name));
+
+    Ok(env
+        .into_iter()
+        .filter(|(name, _)| {
+            config.environment_variables.contains(name)
+                || (detected && ENVIRONMENT_VARIABLES.contains(&name.as_str()))
+        })
+        .collect())
+}
+
+// The process a terminal window runs its commands in: the foreground process of its shell, or
+// the shell itself
+fn get_terminal_environment_pid(
     pid: u32,
     window_id: u32,
-    terminal_command: String,
-) -> Result<Vec<String>, String> {
-    let config = CONFIG.get().unwrap();
-    let shell_pid = fs::read_to_string(format!("/proc/{}/task/{}/children", pid, pid))
-        .map_err(|err| err.to_string())?
+    tabs: Option<&[TerminalTab]>,
+) -> Result<u32> {
+    if let Some(fg_process) = tabs.and_then(|tabs| tabs.first()?.panes.first()?.foreground.as_ref())
+    {
+        return Ok(fg_process.pid);
+    }
+
+    let Some(shell_pid) = get_shell_pid(pid, window_id)? else {
+        return Ok(pid);
+    };
+    match get_foreground_pid(shell_pid) {
+        // The leader of the foreground process group may have exited already
+        Ok(fg_pid) if Path::new(&format!("/proc/{}", fg_pid)).exists() => Ok(fg_pid),
+        _ => Ok(shell_pid),
+    }
+}
+
+fn join_cmd<'a>(parts: impl IntoIterator<Item = &'a str>) -> Result<String> {
+    try_join(parts).map_err(|e| Error::Proc(e.to_string()))
+}
+
+fn split_cmd(cmd: &str) -> Result<Vec<String>> {
+    split(cmd).ok_or_else(|| Error::Config(format!("Invalid command: {}", cmd)))
+}
+
+fn get_child_pids(pid: u32) -> Result<Vec<u32>> {
+    fs::read_to_string(format!("/proc/{}/task/{}/children", pid, pid))?
+        .split_whitespace()
+        .map(|child| {
+            child
+                .parse::<u32>()
+                .map_err(|e| Error::Proc(format!("Invalid child pid of {}: {}", pid, e)))
+        })
+        .collect()
+}
+
+pub(crate) fn get_first_child_pid(pid: u32) -> Result<Option<u32>> {
+    Ok(get_child_pids(pid)?.first().copied())
+}
+
+// The shell of a terminal window. A terminal running several windows in one process has a shell
+// per window, told apart by the WINDOWID most terminals export, and the first one otherwise.
+pub(crate) fn get_shell_pid(pid: u32, window_id: u32) -> Result<Option<u32>> {
+    let children = get_child_pids(pid)?;
+    let window_id = window_id.to_string();
+    Ok(children
+        .iter()
+        .copied()
+        .find(|&child| {
+            get_process_env(child).is_ok_and(|env| env.get("WINDOWID") == Some(&window_id))
+        })
+        .or(children.first().copied()))
+}
+
+// The foreground process group of the terminal a process is attached to
+fn get_foreground_pid(pid: u32) -> Result<u32> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let invalid_stat = || Error::Proc(format!("Invalid stat of {}", pid));
+    stat[(stat.rfind(')').ok_or_else(invalid_stat)? + 1)..]
         .split_whitespace()
-        .next()
-        .unwrap()
-        .to_owned();
+        .nth(5)
+        .and_then(|tpgid| tpgid.parse::<u32>().ok())
+        .ok_or_else(invalid_stat)
+}
+
+// The process running in the foreground of a shell, if any
+fn get_shell_foreground(pid: u32, shell_pid: u32) -> Result<Option<ForegroundProcess>> {
+    let fg_process_pid = get_foreground_pid(shell_pid)?;
+
+    // no foreground process is running
+    if fg_process_pid == shell_pid {
+        return Ok(None);
+    };
 
-    let raw_shell_cmd = match fs::read_to_string(format!("/proc/{}/cmdline", shell_pid)) {
-        Ok(cmd) => cmd,
-        Err(err) => return Err(err.to_string()),
+    match get_process_cmd(fg_process_pid) {
+        Ok(command) => Ok(Some(ForegroundProcess {
+            pid: fg_process_pid,
+            command,
+        })),
+        Err(e) => {
+            eprintln!(
+                "Warning: cannot get process command of {} from terminal with pid of {}: {}",
+                fg_process_pid, pid, e
+            );
+            Ok(None)
+        }
+    }
+}
+
+// The command to revive in place of a foreground command, unless it isn't allowed to be revived
+fn get_revivable_cmd(mut fg_process_cmd: Vec<String>) -> Result<Option<Vec<String>>> {
+    let config = get_config()?;
+    let Some((program, args)) = fg_process_cmd.split_first() else {
+        return Ok(None);
     };
+
+    if !config.terminal_allow_revive_processes.contains(program) {
+        return Ok(None);
+    }
+
+    let args_str = join_cmd(args.iter().map(|a| a.as_str()))?;
+
+    let mut best_score = 0;
+    let mut matched_mapping: Option<&TerminalCommandMapping> = None;
+
+    // mapping regexes are validated when loading the config
+    for mapping in &config.terminal_command_mappings {
+        let mut score = 0;
+        if let Some(re_str) = &mapping.name {
+            if Regex::new(re_str).unwrap().is_match(program) {
+                score += 2;
+            } else {
+                continue;
+            }
+        }
+        if let Some(re_str) = &mapping.args {
+            if Regex::new(re_str).unwrap().is_match(&args_str) {
+                score += 1;
+            } else {
+                continue;
+            }
+        }
+
+        if score > best_score {
+            best_score = score;
+            matched_mapping = Some(mapping);
+        }
+    }
+
+    if let Some(mapping) = matched_mapping {
+        if let Some(command_str) = &mapping.command {
+            let interpolated_command = interpolate_command(command_str, &fg_process_cmd)?;
+            fg_process_cmd = split_cmd(&interpolated_command)?;
+        }
+    }
+
+    Ok(Some(fg_process_cmd))
+}
+
+/// Builds the command reviving a terminal window, with the foreground command of its shell.
+///
+/// With `tabs` captured by a terminal backend, the first pane is revived by the window itself,
+/// which then opens the other tabs and panes.
+fn get_terminal_process_cmd(
+    pid: u32,
+    window_id: u32,
+    terminal_command: String,
+    tabs: Option<(TerminalBackend, &[TerminalTab])>,
+) -> Result<Vec<String>> {
+    let shell_pid = get_shell_pid(pid, window_id)?
+        .ok_or_else(|| Error::Proc(format!("Terminal with pid of {} has no shell", pid)))?;
+
+    let raw_shell_cmd = fs::read_to_string(format!("/proc/{}/cmdline", shell_pid))?;
     let shell_cmd_parts = raw_shell_cmd
         .trim_matches('\0')
         .split('\0')
         .map(|s| s.to_string())
         .collect::<Vec<_>>();
 
-    if shell_cmd_parts.is_empty() {
-        return Err("Shell command not found".into());
-    }
-
-    let shell_cmd = shell_cmd_parts.first().unwrap();
+    let Some(shell_cmd) = shell_cmd_parts.first() else {
+        return Err(Error::Proc("Shell command not found".into()));
+    };
     let shell_name = shell_cmd
         .rsplit_once('/')
         .map(|parts| parts.1)
         .unwrap_or(shell_cmd);
-    let get_process_cmd_parts = || {
-        if shell_name != "bash" && shell_name != "zsh" && shell_name != "fish" && shell_name != "sh"
-        {
+    let shell = Shell::from_name(shell_name);
+    let can_revive_processes = || -> bool {
+        if shell.is_none() {
             eprintln!(
                 "Warning: unknown shell or not a shell: {}, fallback to normal revival",
                 shell_name
             );
-            return Ok(None);
+            return false;
         }
 
-        let is_revived_shell = shell_cmd_parts.join(" ").contains("Revive-Terminal-Mark");
+        let is_revived_shell = shell_cmd_parts.join(" ").contains(REVIVE_MARK);
         if shell_cmd_parts.len() > 1 && !is_revived_shell {
             eprintln!("Warning: shell has additional arguments, fallback to normal revival");
-            return Ok(None);
+            return false;
         }
 
-        let fg_process_pid = match fs::read_to_string(format!("/proc/{}/stat", shell_pid)) {
-            Ok(stat) => stat[(stat.rfind(')').unwrap() + 1)..]
-                .split_whitespace()
-                .nth(5)
-                .unwrap()
-                .parse::<u32>()
-                .unwrap(),
-            Err(err) => return Err(err.to_string()),
-        };
-
-        // no foreground process is running
-        if fg_process_pid == shell_pid.parse::<u32>().unwrap() {
-            return Ok(None);
-        };
+        true
+    }();
 
-        let mut fg_process_cmd = match get_process_cmd(fg_process_pid) {
-            Ok(cmd) => cmd,
-            Err(e) => {
-                eprintln!(
-                    "Warning: cannot get process command of {} from terminal with pid of {}: {}",
-                    fg_process_pid, pid, e
-                );
-                return Ok(None);
-            }
+    // running process cmd inside an interactive shell let it to be run like if we run it manually
+    let revive_in_shell = |fg_process: Option<ForegroundProcess>| -> Result<Vec<String>> {
+        let Some(shell) = shell else {
+            return Ok(vec![shell_cmd.clone()]);
         };
 
-        if fg_process_cmd
-            .first()
-            .map(|proc| config.terminal_allow_revive_processes.contains(proc))
-            .unwrap_or(true)
-        {
-            let (program, args) = fg_process_cmd.split_first().unwrap();
-            let args_str = try_join(args.iter().map(|a| a.as_str())).unwrap();
-
-            let mut best_score = 0;
-            let mut matched_mapping: Option<&TerminalCommandMapping> = None;
-
-            for mapping in &config.terminal_command_mappings {
-                let mut score = 0;
-                if let Some(re_str) = &mapping.name {
-                    if Regex::new(re_str).unwrap().is_match(program) {
-                        score += 2;
-                    } else {
-                        continue;
-                    }
-                }
-                if let Some(re_str) = &mapping.args {
-                    if Regex::new(re_str).unwrap().is_match(&args_str) {
-                        score += 1;
-                    } else {
-                        continue;
-                    }
-                }
-
-                if score > best_score {
-                    best_score = score;
-                    matched_mapping = Some(mapping);
+        let process_cmd = match fg_process.filter(|_| can_revive_processes) {
+            // tmux and screen clients attach again to their session
+            Some(fg_process) => match get_multiplexer_cmd(&fg_process) {
+                Ok(Some(cmd)) => Some(cmd),
+                Ok(None) => get_revivable_cmd(fg_process.command)?,
+                Err(e) => {
+                    eprintln!(
+                        "Warning: cannot find the session of {}: {}",
+                        fg_process.command.join(" "),
+                        e
+                    );
+                    get_revivable_cmd(fg_process.command)?
                 }
-            }
+            },
+            None => None,
+        };
+        let process_line = match process_cmd {
+            Some(parts) => Some(shell.command_line(parts.iter().map(|s| s.as_str()))?),
+            None => None,
+        };
 
-            if let Some(mapping) = matched_mapping {
-                if let Some(command_str) = &mapping.command {
-                    let re = Regex::new(r"\{(\d+)\}").unwrap();
-                    let interpolated_command =
-                        re.replace_all(command_str, |caps: &regex::Captures| {
-                            let index: usize = caps[1].parse().unwrap();
-                            fg_process_cmd.get(index).map_or("".to_string(), |s| {
-                                shlex::try_quote(s).unwrap().to_string()
-                            })
-                        });
-                    fg_process_cmd = split(&interpolated_command).unwrap();
-                }
-            }
+        let mut cmd_parts = vec![shell_cmd.clone()];
+        cmd_parts.extend(shell.interactive_args(shell_cmd, process_line.as_deref())?);
+        Ok(cmd_parts)
+    };
 
-            return Ok(Some(fg_process_cmd));
-        }
+    let (first_pane_cmd, rebuild_script) = match tabs {
+        Some((backend, tabs)) => (
+            tabs.first()
+                .and_then(|tab| tab.panes.first())
+                .and_then(|pane| pane.foreground.clone()),
+            terminal::rebuild_script(backend, tabs, |pane| {
+                let cmd_parts = revive_in_shell(pane.foreground.clone())?;
+                join_cmd(cmd_parts.iter().map(|s| s.as_str()))
+            })?,
+        ),
+        None if can_revive_processes => (get_shell_foreground(pid, shell_pid)?, None),
+        None => (None, None),
+    };
 
-        Ok(None)
+    // the title should be set from a non-interactive shell, as the shell can mess with the window title in interactive mode
+    // the 0.5s sleep helps the title be displayed long enough to be detected by the swallow window
+    // unknown shells are only started once the title is set from sh
+    let (wrapper_shell, wrapper_cmd) = match shell {
+        Some(shell) => (shell, shell_cmd.as_str()),
+        None => (Shell::Posix, "sh"),
     };
-    // running process cmd inside an interactive shell let it to be run like if we run it manually
-    let process_cmd = get_process_cmd_parts()?
-        .map(|parts| try_join(parts.iter().map(|s| s.as_str())).unwrap())
-        .unwrap_or("true".to_string());
-    let process_cmd_with_shell_fallback = format!(
-        "true Revive-Terminal-Mark; {}; exec {}",
-        process_cmd, shell_cmd
-    );
-    let process_cmd_parts = [
-        shell_cmd,
-        "-i",
-        "-c",
-        process_cmd_with_shell_fallback.as_str(),
+    let title = format!("Revive-Terminal-Window-{}", window_id);
+    let mut lines = vec![
+        wrapper_shell.command_line(["printf", "\\033]0;%s\\007", title.as_str()])?,
+        wrapper_shell.command_line(["sleep", "0.5"])?,
     ];
+    // the other tabs and panes are opened once the window is swallowed, from inside it
+    if let Some(script) = rebuild_script {
+        lines.push(wrapper_shell.command_line(["sh", "-c", script.as_str()])?);
+    }
+    let first_pane_parts = revive_in_shell(first_pane_cmd)?;
+    lines.push(wrapper_shell.exec_line(first_pane_parts.iter().map(|s| s.as_str()))?);
+    let process_with_title_cmd = lines.join("; ");
+    let cmd_parts = [wrapper_cmd, "-c", process_with_title_cmd.as_str()];
 
-    // the title echo should be run on non-interactive shell, as the shell can mess with the window title in interactive mode
-    // the 0.5s sleep helps the title be displayed long enough to be detected by the swallow window
-    let process_with_title_cmd = format!(
-        "echo -ne \"\\033]0;Revive-Terminal-Window-{}\\007\"; sleep 0.5; exec {}",
-        window_id,
-        &try_join(process_cmd_parts).unwrap(),
-    );
-    let cmd_parts = [shell_cmd, "-c", process_with_title_cmd.as_str()];
-
-    let terminal_cmd_parts =
-        split(&terminal_command.replace("{cmd}", &try_join(cmd_parts).unwrap()))
-            .unwrap_or_else(|| panic!("Invalid terminal command: {}", terminal_command));
-
-    Ok(terminal_cmd_parts)
+    split_cmd(&terminal_command.replace("{cmd}", &join_cmd(cmd_parts)?))
 }
 
-fn get_process_cwd(pid: u32, is_terminal: bool) -> io::Result<String> {
+// Replaces `{N}` placeholders with the shell-quoted N-th part of the original command
+fn interpolate_command(command_str: &str, original_cmd_parts: &[String]) -> Result<String> {
+    let re = Regex::new(r"\{(\d+)\}").unwrap();
+    let mut err = None;
+    let interpolated = re.replace_all(command_str, |caps: &regex::Captures| {
+        caps[1]
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| original_cmd_parts.get(index))
+            .map_or(String::new(), |s| match shlex::try_quote(s) {
+                Ok(quoted) => quoted.to_string(),
+                Err(e) => {
+                    err = Some(Error::Proc(e.to_string()));
+                    String::new()
+                }
+            })
+    });
+
+    match err {
+        Some(e) => Err(e),
+        None => Ok(interpolated.into_owned()),
+    }
+}
+
+fn get_process_cwd(pid: u32, terminal_window_id: Option<u32>) -> Result<String> {
     let mut path = fs::read_link(format!("/proc/{}/cwd", pid))?;
-    if is_terminal {
+    if let Some(window_id) = terminal_window_id {
         // If the program is a terminal emulator, get the working
-        // directory from its first subprocess.
-        if let Some(first_child_pid) =
-            fs::read_to_string(format!("/proc/{}/task/{}/children", pid, pid))?
-                .split_whitespace()
-                .next()
-        {
-            path = fs::read_link(format!("/proc/{}/cwd", first_child_pid))?;
+        // directory from the shell of the window.
+        if let Some(shell_pid) = get_shell_pid(pid, window_id)? {
+            path = fs::read_link(format!("/proc/{}/cwd", shell_pid))?;
         }
     }
 
-    Ok(path.into_os_string().into_string().unwrap())
+    path.into_os_string()
+        .into_string()
+        .map_err(|path| Error::Proc(format!("Non UTF-8 working directory: {:?}", path)))
 }
 
-pub fn save_processes(windows: Vec<i3_tree::Window>) {
-    let config = CONFIG.get().unwrap();
-    let base_dirs = BaseDirs::new().expect("Failed to get base directories");
+pub fn save_processes(windows: Vec<i3_tree::Window>) -> Result<Vec<Process>> {
+    let config = get_config()?;
     let mut once_mappings = HashSet::new();
     let mut processed_pids = HashSet::new();
-    let processes = windows
-        .iter()
-        .filter_map(|w| {
-            if w.is_placeholder {
-                return None;
+    let mut processes = vec![];
+    let placeholder_re = Regex::new(r"\{(\d+)\}").unwrap();
+
+    for w in windows.iter() {
+        if w.is_placeholder {
+            continue;
+        }
+
+        let pid = match get_pid(w.id) {
+            Ok(pid) => pid,
+            Err(err) => {
+                eprintln!("Warning: Cannot found pid of window {}: {}", w.id, err);
+                continue;
             }
+        };
+
+        // Apps running all their windows in one process need a process per window
+        let multi_window_app = w.class.as_deref().and_then(|class| {
+            config
+                .multi_window_apps
+                .iter()
+                .find(|app| Regex::new(&app.class).unwrap().is_match(class))
+        });
+        if !processed_pids.insert(pid) && multi_window_app.is_none() {
+            continue;
+        }
 
-            let maybe_pid = get_pid(w.id);
-            if let Err(err) = maybe_pid {
-                eprintln!("Warning: Cannot found pid of window {}: {:?}", w.id, err);
-                return None;
+        let mut command: Option<Vec<String>> = None;
+        let mut working_directory: Option<String> = None;
+        let mut matched_mapping: Option<&WindowCommandMapping> = None;
+        let mut matched_mapping_idx: Option<usize> = None;
+        let mut best_score = 0;
+        // mapping regexes are validated when loading the config
+        for (i, mapping) in config.window_command_mappings.iter().enumerate() {
+            let title_regex = &mapping
+                .title
+                .as_ref()
+                .map(|str| Regex::new(str.as_str()).unwrap());
+            let class_regex = &mapping
+                .class
+                .as_ref()
+                .map(|str| Regex::new(str.as_str()).unwrap());
+
+            let mut score = 0;
+            if let Some(re) = title_regex.as_ref() {
+                if re.is_match(&w.name) {
+                    score += 2;
+                } else {
+                    continue;
+                }
+            }
+            if let Some(re) = class_regex.as_ref() {
+                if w.class
+                    .as_deref()
+                    .map(|class| re.is_match(class))
+                    .unwrap_or(false)
+                {
+                    score += 1;
+                } else {
+                    continue;
+                }
             }
-            let pid = maybe_pid.unwrap();
 
-            if !processed_pids.insert(pid) {
-                return None;
+            if score > best_score {
+                best_score = score;
+                matched_mapping = Some(mapping);
+                matched_mapping_idx = Some(i);
             }
+        }
 
-            let mut command: Option<Vec<String>> = None;
-            let mut working_directory: Option<String> = None;
-            let mut matched_mapping: Option<&WindowCommandMapping> = None;
-            let mut matched_mapping_idx: Option<usize> = None;
-            let mut best_score = 0;
-            for (i, mapping) in config.window_command_mappings.iter().enumerate() {
-                let title_regex = &mapping
-                    .title
-                    .as_ref()
-                    .map(|str| Regex::new(str.as_str()).unwrap());
-                let class_regex = &mapping
-                    .class
-                    .as_ref()
-                    .map(|str| Regex::new(str.as_str()).unwrap());
+        if let Some(mapping) = matched_mapping {
+            if mapping.ignored.is_some_and(|ignored| ignored) {
+                continue;
+            }
 
-                let mut score = 0;
-                if let Some(re) = title_regex.as_ref() {
-                    if re.is_match(&w.name) {
-                        score += 2;
-                    } else {
+            if let Some(mapping_idx) = matched_mapping_idx {
+                if mapping.once.is_some_and(|once| once) {
+                    if once_mappings.contains(&mapping_idx) {
                         continue;
                     }
-                }
-                if let Some(re) = class_regex.as_ref() {
-                    if w.class
-                        .as_deref()
-                        .map(|class| re.is_match(class))
-                        .unwrap_or(false)
-                    {
-                        score += 1;
-                    } else {
-                        continue;
-                    }
-                }
 
-                if score > best_score {
-                    best_score = score;
-                    matched_mapping = Some(mapping);
-                    matched_mapping_idx = Some(i);
+                    once_mappings.insert(mapping_idx);
                 }
             }
 
-            if let Some(mapping) = matched_mapping {
-                if mapping.ignored.is_some_and(|ignored| ignored) {
-                    return None;
-                }
-
-                if let Some(mapping_idx) = matched_mapping_idx {
-                    if mapping.once.is_some_and(|once| once) {
-                        if once_mappings.contains(&mapping_idx) {
-                            return None;
+            if let Some(command_str) = &mapping.command {
+                let interpolated_command = if placeholder_re.is_match(command_str) {
+                    match get_process_cmd(pid) {
+                        Ok(original_cmd_parts) => {
+                            Some(interpolate_command(command_str, &original_cmd_parts)?)
                         }
-
-                        once_mappings.insert(mapping_idx);
+                        Err(_) => None,
                     }
-                }
-
-                if let Some(command_str) = &mapping.command {
-                    let re = Regex::new(r"\{(\d+)\}").unwrap();
-                    let interpolated_command = if re.is_match(command_str) {
-                        get_process_cmd(pid).ok().map(|original_cmd_parts| {
-                            re.replace_all(command_str, |caps: &regex::Captures| {
-                                let index: usize = caps[1].parse().unwrap();
-                                original_cmd_parts.get(index).map_or("".to_string(), |s| {
-                                    shlex::try_quote(s).unwrap().to_string()
-                                })
-                            })
-                            .into_owned()
-                        })
-                    } else {
-                        None
-                    };
-                    command = split(interpolated_command.as_deref().unwrap_or(command_str));
-                }
-                if let Some(working_directory_str) = &mapping.working_directory {
-                    working_directory = Some(working_directory_str.clone());
-                }
+                } else {
+                    None
+                };
+                command = Some(split_cmd(
+                    interpolated_command.as_deref().unwrap_or(command_str),
+                )?);
+            }
+            if let Some(working_directory_str) = &mapping.working_directory {
+                working_directory = Some(working_directory_str.clone());
             }
+        }
 
-            let terminal_command = w
-                .class
-                .as_ref()
-                .and_then(|class| config.terminal_revive_commands.get(class));
-
-            Some(Process {
-                command: command.unwrap_or_else(|| {
-                    terminal_command
-                        .map(|cmd| get_terminal_process_cmd(pid, w.id, cmd.to_string()).unwrap())
-                        .unwrap_or_else(|| get_process_cmd(pid).unwrap())
-                }),
-                working_directory: working_directory
-                    .unwrap_or_else(|| get_process_cwd(pid, terminal_command.is_some()).unwrap()),
-            })
-        })
-        .collect::<Vec<_>>();
+        let terminal_command = w
+            .class
+            .as_ref()
+            .and_then(|class| config.terminal_revive_commands.get(class));
+
+        let terminal_backend = w
+            .class
+            .as_ref()
+            .and_then(|class| config.terminal_backends.get(class));
+        let terminal_tabs = match (&command, terminal_command, terminal_backend) {
+            (None, Some(_), Some(&backend)) => match capture_tabs(backend, pid, w.id, &w.name) {
+                Ok(tabs) => Some((backend, tabs)),
+                Err(e) => {
+                    eprintln!(
+                        "Warning: Cannot read the tabs of terminal window {}: {}, fallback to its first shell",
+                        w.id, e
+                    );
+                    None
+                }
+            },
+            _ => None,
+        };
 
-    let json = serde_json::to_string_pretty(&processes).expect("Failed to serialize");
+        // The process may have exited since the tree was read, which only loses its window
+        let revive = || -> Result<(Vec<String>, String)> {
+            let command = match command {
+                Some(command) => command,
+                None => match (
+                    terminal_command,
+                    multi_window_app.and_then(|app| app.command.as_ref()),
+                ) {
+                    (Some(cmd), _) => get_terminal_process_cmd(
+                        pid,
+                        w.id,
+                        cmd.to_string(),
+                        terminal_tabs
+                            .as_ref()
+                            .map(|(backend, tabs)| (*backend, tabs.as_slice())),
+                    )?,
+                    (None, Some(new_window_cmd)) => split_cmd(new_window_cmd)?,
+                    (None, None) => get_process_cmd(pid)?,
+                },
+            };
+            let working_directory = match working_directory {
+                Some(working_directory) => working_directory,
+                None => match terminal_tabs
+                    .as_ref()
+                    .and_then(|(_, tabs)| tabs.first()?.panes.first())
+                    .filter(|pane| !pane.cwd.is_empty())
+                {
+                    Some(pane) => pane.cwd.clone(),
+                    None => get_process_cwd(pid, terminal_command.map(|_| w.id))?,
+                },
+            };
+            Ok((command, working_directory))
+        };
+        let (command, working_directory) = match revive() {
+            Ok(revived) => revived,
+            Err(err) => {
+                eprintln!(
+                    "Warning: Cannot save the process of window {}: {}",
+                    w.id, err
+                );
+                continue;
+            }
+        };
 
-    let mut dir = base_dirs.data_local_dir().to_path_buf();
-    dir.push("i3-revive");
-    fs::create_dir_all(&dir).expect("Failed to create directory");
+        // A terminal's environment is set up in its shell, e.g. by activating a virtualenv
+        let environment_pid = match terminal_command {
+            Some(_) => get_terminal_environment_pid(
+                pid,
+                w.id,
+                terminal_tabs.as_ref().map(|(_, tabs)| tabs.as_slice()),
+            ),
+            None => Ok(pid),
+        };
+        let environment = environment_pid
+            .and_then(get_saved_environment)
+            .unwrap_or_else(|e| {
+                eprintln!(
+                    "Warning: Cannot read the environment of window {}: {}",
+                    w.id, e
+                );
+                BTreeMap::new()
+            });
+
+        processes.push(Process {
+            command,
+            working_directory,
+            environment,
+            workspace: w.workspace.clone(),
+            window: Some(ProcessWindow {
+                id: w.id,
+                class: w.class.clone(),
+                swallows: get_swallow_criteria(Some(w.id), &w.properties, config),
+            }),
+        });
+    }
 
-    let mut file_path = dir.clone();
-    file_path.push("processes.json");
-    fs::write(file_path, json).expect("Failed to write file");
+    Ok(processes)
 }
 
-pub fn restore_processes() {
-    let base_dirs = BaseDirs::new().expect("Failed to get base directories");
-    let mut file_path = base_dirs.data_local_dir().to_path_buf();
-    file_path.push("i3-revive");
-    file_path.push("processes.json");
+/// Returns the commands of the processes of the given windows, to tell whether a process saved
+/// without its window is already running.
+pub fn get_running_commands(window_ids: &[u32]) -> HashSet<Vec<String>> {
+    window_ids
+        .iter()
+        .filter_map(|&window_id| get_pid(window_id).ok())
+        .filter_map(|pid| get_process_cmd(pid).ok())
+        .collect()
+}
 
-    if !file_path.exists() {
-        eprintln!("process file is missing");
-        std::process::exit(1);
-    }
+/// A process spawned when restoring, with where its output goes.
+#[derive(Debug)]
+pub struct SpawnedProcess<'a> {
+    pub process: &'a Process,
+    /// The spawned child, or why it couldn't be spawned.
+    pub child: Result<Child>,
+    pub stdout_log: PathBuf,
+    pub stderr_log: PathBuf,
+}
 
-    let json_content = fs::read_to_string(&file_path).expect("Failed to read processes.json file");
-    let processes: Vec<Process> =
-        serde_json::from_str(&json_content).expect("Failed to deserialize processes.json");
+/// Spawns the processes, logging their output under `log_dir()`.
+///
+/// A process that can't be spawned doesn't prevent the others from being spawned.
+pub fn restore_processes<'a>(
+    processes: impl IntoIterator<Item = &'a Process>,
+) -> Result<Vec<SpawnedProcess<'a>>> {
+    let log_dir = log_dir()?;
+    let mut spawned = vec![];
 
     for process in processes {
         if let Some((program, args)) = process.command.split_first() {
-            let process_name = program.split('/').next_back().unwrap();
+            let process_name = program.rsplit('/').next().unwrap_or(program);
             let timestamp = Local::now().format("%Y-%m-%d-%H-%M-%S-%f");
+            let log_name = format!("{}-{}.log", process_name, timestamp);
+            let stdout_log = log_dir.join("stdout").join(&log_name);
+            let stderr_log = log_dir.join("stderr").join(&log_name);
 
-            let mut stdout_log_path = base_dirs.state_dir().unwrap().to_path_buf();
-            stdout_log_path.push("i3-revive");
-            stdout_log_path.push("stdout");
-            stdout_log_path.push(format!("{}-{}.log", process_name, timestamp));
-            std::fs::create_dir_all(stdout_log_path.parent().unwrap()).unwrap();
-            let stdout_log =
-                File::create(stdout_log_path).expect("Failed to create stdout log file");
-
-            let mut stderr_log_path = base_dirs.state_dir().unwrap().to_path_buf();
-            stderr_log_path.push("i3-revive");
-            stderr_log_path.push("stderr");
-            stderr_log_path.push(format!("{}-{}.log", process_name, timestamp));
-            std::fs::create_dir_all(stderr_log_path.parent().unwrap()).unwrap();
-            let stderr_log =
-                File::create(stderr_log_path).expect("Failed to create stderr log file");
-
-            #[allow(clippy::zombie_processes)]
-            Command::new(program)
-                .args(args)
-                .current_dir(&process.working_directory)
-                .process_group(0)
-                .stdin(Stdio::null())
-                .stdout(Stdio::from(stdout_log))
-                .stderr(Stdio::from(stderr_log))
-                .spawn()
-                .expect("Failed to spawn process");
+            let child = spawn_logged(program, args, process, &stdout_log, &stderr_log);
+            if let Err(e) = &child {
+                eprintln!("Warning: Failed to spawn {}: {}", program, e);
+            }
+
+            spawned.push(SpawnedProcess {
+                process,
+                child,
+                stdout_log,
+                stderr_log,
+            });
         }
     }
-}
 
-pub fn remove_processes() {
-    let base_dirs = BaseDirs::new().expect("Failed to get base directories");
-    let mut file_path = base_dirs.data_local_dir().to_path_buf();
-    file_path.push("i3-revive");
-    file_path.push("processes.json");
+    Ok(spawned)
+}
 
-    if file_path.exists() {
-        fs::remove_file(&file_path).expect("Failed to remove processes.json file");
+fn spawn_logged(
+    program: &str,
+    args: &[String],
+    process: &Process,
+    stdout_log_path: &Path,
+    stderr_log_path: &Path,
+) -> Result<Child> {
+    for path in [stdout_log_path, stderr_log_path] {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
     }
+    let stdout_log = File::create(stdout_log_path)?;
+    let stderr_log = File::create(stderr_log_path)?;
+
+    Ok(Command::new(program)
+        .args(args)
+        .current_dir(&process.working_directory)
+        .envs(&process.environment)
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(stdout_log))
+        .stderr(Stdio::from(stderr_log))
+        .spawn()?)
 }
@@ -0,0 +1,618 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::ptr;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use shlex::try_join;
+
+use crate::error::{Error, Result};
+use crate::i3_tree::{
+    adopt_windows, find_node, get_layout_placeholders, kill_placeholders, restore_focus,
+    restore_marks, restore_workspaces, AppendedContainer, LayoutPlaceholder,
+};
+use crate::i3ipc::get_tree;
+use crate::metadata::{
+    map_workspace_outputs, restore_metadata, restore_workspace_outputs, wait_for_placeholders,
+};
+use crate::output::OutputInfo;
+use crate::process::{get_running_commands, restore_processes, Process, SpawnedProcess};
+use crate::session::SessionData;
+
+/// How long to wait for the revived windows, and what to do with those that never show up.
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    /// Time to wait for every placeholder to swallow a window, or `None` to wait forever.
+    pub timeout: Option<Duration>,
+    /// Whether to close the placeholders still waiting for a window when the timeout expires.
+    pub kill_pending: bool,
+    /// Whether to adopt already running windows into the placeholders they match, spawning only
+    /// the processes of the others.
+    pub merge: bool,
+    /// The workspaces to restore, leaving the others and the scratchpad alone, or all of them
+    /// if empty.
+    pub workspaces: Vec<String>,
+}
+
+/// Restores a saved session: appends the workspace layouts and moves them to their outputs,
+/// spawns the processes to be swallowed by them, then re-applies marks and focus and brings back
+/// the visible workspaces once every placeholder is filled.
+///
+/// If the timeout expires first, the rest is still restored as far as possible, and the report
+/// is marked as timed out.
+pub fn restore_session(
+    stream: &mut UnixStream,
+    data: &SessionData,
+    options: &RestoreOptions,
+) -> Result<RestoreReport> {
+    let selected;
+    let data = if options.workspaces.is_empty() {
+        data
+    } else {
+        selected = data.select_workspaces(&options.workspaces)?;
+        &selected
+    };
+
+    let mut appended = restore_workspaces(stream, &data.workspaces, &data.scratchpad, options)?;
+    let links = link_processes(&appended, &data.processes);
+    let processes = if options.merge {
+        let adopted_windows = adopt_windows(stream, &mut appended)?;
+        let adopted = appended
+            .iter()
+            .zip(&links)
+            .filter(|(container, _)| container.adopted)
+            .filter_map(|(_, process)| *process)
+            .collect::<Vec<_>>();
+
+        // Processes saved without their window can only be recognized by their command
+        let running_commands = get_running_commands(&adopted_windows);
+        data.processes
+            .iter()
+            .filter(|process| match process.window {
+                Some(_) => !adopted.iter().any(|adopted| ptr::eq(*adopted, *process)),
+                None => !running_commands.contains(&process.command),
+            })
+            .collect()
+    } else {
+        data.processes.iter().collect::<Vec<_>>()
+    };
+    restore_workspace_outputs(stream, &data.metadata, &data.provenance.outputs)?;
+    let mut spawned = restore_processes(processes)?;
+
+    let pending = wait_for_placeholders(stream, options.timeout)?;
+    let report = build_report(
+        stream,
+        &appended,
+        &links,
+        &mut spawned,
+        !pending.is_empty(),
+        options,
+    )?;
+    if !pending.is_empty() {
+        eprintln!("Placeholders still waiting for a window:");
+        for placeholder in &pending {
+            eprintln!("  - {:?}", placeholder.name);
+        }
+
+        if options.kill_pending {
+            kill_placeholders(&pending)?;
+        }
+    }
+
+    restore_marks(stream, &appended)?;
+    restore_focus(stream, &data.workspaces, &appended)?;
+    restore_metadata(stream, &data.metadata)?;
+
+    Ok(report)
+}
+
+// Pairs each appended placeholder with the process saved for the window it swallows, if any
+fn link_processes<'a>(
+    appended: &[AppendedContainer],
+    processes: &'a [Process],
+) -> Vec<Option<&'a Process>> {
+    let mut linked = HashSet::new();
+
+    appended
+        .iter()
+        .map(|container| {
+            let swallows = container
+                .layout
+                .get("swallows")?
+                .as_array()?
+                .first()?
+                .as_object()?;
+            let (i, process) = processes.iter().enumerate().find(|(i, process)| {
+                !linked.contains(i)
+                    && process.workspace.as_deref() == container.workspace
+                    && process
+                        .window
+                        .as_ref()
+                        .is_some_and(|window| &window.swallows == swallows)
+            })?;
+
+            linked.insert(i);
+            Some(process)
+        })
+        .collect()
+}
+
+/// What happened to each saved window and process during [`restore_session`].
+#[derive(Serialize, Debug)]
+pub struct RestoreReport {
+    /// Whether the restore stopped waiting for windows before every placeholder was filled.
+    pub timed_out: bool,
+    pub windows: Vec<WindowReport>,
+    pub processes: Vec<ProcessReport>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WindowReport {
+    /// The workspace the window was restored to, or `None` for the scratchpad.
+    pub workspace: Option<String>,
+    pub name: Option<String>,
+    pub swallows: Vec<Map<String, Value>>,
+    pub status: WindowStatus,
+    /// The process spawned for the window, if it was saved along with it.
+    pub command: Option<Vec<String>>,
+    pub pid: Option<u32>,
+    pub stdout_log: Option<PathBuf>,
+    pub stderr_log: Option<PathBuf>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowStatus {
+    /// Its placeholder swallowed a window.
+    Swallowed,
+    /// An already running window took the place of its placeholder.
+    Adopted,
+    /// Its placeholder is still waiting for a window.
+    Pending,
+    /// Its placeholder was closed without swallowing a window, or its process failed.
+    Failed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProcessReport {
+    pub command: Vec<String>,
+    pub working_directory: String,
+    pub pid: Option<u32>,
+    pub status: ProcessStatus,
+    /// The exit code of a process that has already exited.
+    pub exit_code: Option<i32>,
+    /// Why the process couldn't be spawned.
+    pub error: Option<String>,
+    pub stdout_log: PathBuf,
+    pub stderr_log: PathBuf,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessStatus {
+    Running,
+    /// Exited successfully, e.g. after handing its window over to another process.
+    Exited,
+    /// Couldn't be spawned, or exited with an error.
+    Failed,
+}
+
+/// The formats a [`RestoreReport`] can be printed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Table,
+    Json,
+}
+
+impl RestoreReport {
+    /// Returns the number of windows whose placeholder didn't swallow a window.
+    pub fn unswallowed(&self) -> usize {
+        self.windows
+            .iter()
+            .filter(|window| {
+                window.status != WindowStatus::Swallowed && window.status != WindowStatus::Adopted
+            })
+            .count()
+    }
+
+    pub fn format(&self, format: ReportFormat) -> Result<String> {
+        match format {
+            ReportFormat::Table => Ok(self.to_string()),
+            ReportFormat::Json => serde_json::to_string_pretty(self)
+                .map(|json| json + "\n")
+                .map_err(|e| Error::Session(e.to_string())),
+        }
+    }
+}
+
+fn build_report(
+    stream: &mut UnixStream,
+    appended: &[AppendedContainer],
+    links: &[Option<&Process>],
+    spawned: &mut [SpawnedProcess],
+    timed_out: bool,
+    options: &RestoreOptions,
+) -> Result<RestoreReport> {
+    let root = get_tree(stream)?;
+
+    let processes: Vec<ProcessReport> = spawned
+        .iter_mut()
+        .map(|spawned| {
+            let (pid, status, exit_code, error) = match &mut spawned.child {
+                Ok(child) => match child.try_wait() {
+                    Ok(None) => (Some(child.id()), ProcessStatus::Running, None, None),
+                    Ok(Some(exit)) if exit.success() => {
+                        (Some(child.id()), ProcessStatus::Exited, exit.code(), None)
+                    }
+                    Ok(Some(exit)) => (Some(child.id()), ProcessStatus::Failed, exit.code(), None),
+                    Err(e) => (
+                        Some(child.id()),
+                        ProcessStatus::Failed,
+                        None,
+                        Some(e.to_string()),
+                    ),
+                },
+                Err(e) => (None, ProcessStatus::Failed, None, Some(e.to_string())),
+            };
+
+            ProcessReport {
+                command: spawned.process.command.clone(),
+                working_directory: spawned.process.working_directory.clone(),
+                pid,
+                status,
+                exit_code,
+                error,
+                stdout_log: spawned.stdout_log.clone(),
+                stderr_log: spawned.stderr_log.clone(),
+            }
+        })
+        .collect();
+
+    let windows = appended
+        .iter()
+        .zip(links)
+        .filter_map(|(container, process)| {
+            let swallows = container.layout.get("swallows")?.as_array()?;
+            let process = process.and_then(|process| {
+                spawned
+                    .iter()
+                    .position(|spawned| ptr::eq(spawned.process, process))
+                    .map(|i| &processes[i])
+            });
+
+            let status = match find_node(&root, container.id) {
+                Some(_) if container.adopted => WindowStatus::Adopted,
+                Some(node) if node.swallows.is_empty() => WindowStatus::Swallowed,
+                Some(_) if process.is_some_and(|p| p.status == ProcessStatus::Failed) => {
+                    WindowStatus::Failed
+                }
+                Some(_) if !options.kill_pending => WindowStatus::Pending,
+                _ => WindowStatus::Failed,
+            };
+
+            Some(WindowReport {
+                workspace: container.workspace.map(|ws| ws.to_string()),
+                name: container
+                    .layout
+                    .get("name")
+                    .and_then(|name| name.as_str())
+                    .map(|name| name.to_string()),
+                swallows: swallows
+                    .iter()
+                    .filter_map(|criteria| criteria.as_object().cloned())
+                    .collect(),
+                status,
+                command: process.map(|p| p.command.clone()),
+                pid: process.and_then(|p| p.pid),
+                stdout_log: process.map(|p| p.stdout_log.clone()),
+                stderr_log: process.map(|p| p.stderr_log.clone()),
+            })
+        })
+        .collect();
+
+    Ok(RestoreReport {
+        timed_out,
+        windows,
+        processes,
+    })
+}
+
+impl fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Windows:")?;
+        writeln!(
+            f,
+            "  {:<9}  {:>7}  {:<16}  {:<24}  SWALLOWS",
+            "STATUS", "PID", "WORKSPACE", "NAME"
+        )?;
+        for window in &self.windows {
+            writeln!(
+                f,
+                "  {:<9}  {:>7}  {:<16}  {:<24}  {}",
+                format!("{:?}", window.status).to_lowercase(),
+                window.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+                window.workspace.as_deref().unwrap_or("(scratchpad)"),
+                format!("{:?}", window.name.as_deref().unwrap_or_default()),
+                format_swallows(&window.swallows)
+            )?;
+        }
+
+        writeln!(f, "Processes:")?;
+        writeln!(f, "  {:<9}  {:>7}  COMMAND", "STATUS", "PID")?;
+        for process in &self.processes {
+            let command = try_join(process.command.iter().map(|s| s.as_str()))
+                .unwrap_or_else(|_| process.command.join(" "));
+            writeln!(
+                f,
+                "  {:<9}  {:>7}  {}",
+                format!("{:?}", process.status).to_lowercase(),
+                process.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+                command
+            )?;
+            if let Some(exit_code) = process.exit_code {
+                writeln!(f, "  {:<9}  {:>7}  exit code: {}", "", "", exit_code)?;
+            }
+            if let Some(error) = &process.error {
+                writeln!(f, "  {:<9}  {:>7}  error: {}", "", "", error)?;
+            }
+            writeln!(
+                f,
+                "  {:<9}  {:>7}  stdout: {}",
+                "",
+                "",
+                process.stdout_log.display()
+            )?;
+            writeln!(
+                f,
+                "  {:<9}  {:>7}  stderr: {}",
+                "",
+                "",
+                process.stderr_log.display()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What [`restore_session`] would do, without doing any of it.
+#[derive(Debug)]
+pub struct RestorePlan {
+    /// Whether the current outputs were known, so that workspace outputs could be remapped.
+    pub outputs_known: bool,
+    pub workspaces: Vec<PlannedWorkspace>,
+    pub scratchpad: Vec<LayoutPlaceholder>,
+    pub processes: Vec<PlannedProcess>,
+    pub visible_workspaces: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct PlannedWorkspace {
+    pub name: String,
+    /// The output the workspace was saved on.
+    pub saved_output: Option<String>,
+    /// The output the workspace would be moved to, if it is moved at all.
+    pub output: Option<String>,
+    pub placeholders: Vec<LayoutPlaceholder>,
+}
+
+#[derive(Debug)]
+pub struct PlannedProcess {
+    pub command: Vec<String>,
+    pub working_directory: String,
+    pub environment: BTreeMap<String, String>,
+}
+
+/// Plans the restore of a session, with workspace outputs remapped onto the `current_outputs`
+/// when they are known.
+pub fn plan_restore(
+    data: &SessionData,
+    current_outputs: Option<&[OutputInfo]>,
+) -> Result<RestorePlan> {
+    let output_map = match current_outputs {
+        Some(current) => map_workspace_outputs(&data.metadata, &data.provenance.outputs, current)?,
+        None => HashMap::new(),
+    };
+
+    Ok(RestorePlan {
+        outputs_known: current_outputs.is_some(),
+        workspaces: data
+            .workspaces
+            .iter()
+            .map(|layout| {
+                let saved_output = data.metadata.workspace_outputs.get(&layout.name).cloned();
+                PlannedWorkspace {
+                    name: layout.name.clone(),
+                    output: saved_output
+                        .as_ref()
+                        .and_then(|saved| output_map.get(saved).cloned()),
+                    saved_output,
+                    placeholders: get_layout_placeholders(&layout.nodes),
+                }
+            })
+            .collect(),
+        scratchpad: get_layout_placeholders(&data.scratchpad),
+        processes: data
+            .processes
+            .iter()
+            .map(|process| PlannedProcess {
+                command: process.command.clone(),
+                working_directory: process.working_directory.clone(),
+                environment: process.environment.clone(),
+            })
+            .collect(),
+        visible_workspaces: data.metadata.visible_workspaces.clone(),
+    })
+}
+
+impl fmt::Display for RestorePlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Workspaces:")?;
+        for ws in &self.workspaces {
+            write!(f, "  {}", ws.name)?;
+            match (&ws.saved_output, &ws.output) {
+                (None, _) => {}
+                (Some(saved), _) if !self.outputs_known => write!(f, " saved on {}", saved)?,
+                (Some(saved), Some(output)) if saved == output => write!(f, " on {}", output)?,
+                (Some(saved), Some(output)) => write!(f, " on {} (saved on {})", output, saved)?,
+                (Some(saved), None) => {
+                    write!(f, " left in place (saved on {}, not connected)", saved)?
+                }
+            }
+            writeln!(
+                f,
+                ": append_layout with {} placeholder(s)",
+                ws.placeholders.len()
+            )?;
+            for placeholder in &ws.placeholders {
+                write_placeholder(f, placeholder)?;
+            }
+        }
+
+        if !self.scratchpad.is_empty() {
+            writeln!(
+                f,
+                "Scratchpad: append_layout with {} placeholder(s), then move scratchpad",
+                self.scratchpad.len()
+            )?;
+            for placeholder in &self.scratchpad {
+                write_placeholder(f, placeholder)?;
+            }
+        }
+
+        writeln!(f, "Processes:")?;
+        for process in &self.processes {
+            let command = try_join(process.command.iter().map(|s| s.as_str()))
+                .unwrap_or_else(|_| process.command.join(" "));
+            writeln!(f, "  - in {}: {}", process.working_directory, command)?;
+            if !process.environment.is_empty() {
+                let names = process
+                    .environment
+                    .keys()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>();
+                writeln!(f, "    with {}", names.join(", "))?;
+            }
+        }
+
+        writeln!(f, "Visible workspaces, focused last:")?;
+        for ws_name in &self.visible_workspaces {
+            writeln!(f, "  - {}", ws_name)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn write_placeholder(f: &mut fmt::Formatter, placeholder: &LayoutPlaceholder) -> fmt::Result {
+    writeln!(
+        f,
+        "    - {:?} swallows {}",
+        placeholder.name.as_deref().unwrap_or_default(),
+        format_swallows(&placeholder.swallows)
+    )
+}
+
+fn format_swallows(swallows: &[Map<String, Value>]) -> String {
+    swallows
+        .iter()
+        .map(|criteria| {
+            criteria
+                .iter()
+                .map(|(key, value)| match value {
+                    Value::String(value) => format!("{}={:?}", key, value),
+                    value => format!("{}={}", key, value),
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn swallows(class: &str) -> Map<String, Value> {
+        let mut swallows = Map::new();
+        swallows.insert("class".to_string(), json!(format!("^{}$", class)));
+        swallows
+    }
+
+    fn placeholder(class: &str) -> Value {
+        json!({ "swallows": [swallows(class)] })
+    }
+
+    fn appended<'a>(layouts: &'a [(Value, Option<&'a str>)]) -> Vec<AppendedContainer<'a>> {
+        layouts
+            .iter()
+            .enumerate()
+            .map(|(i, (layout, workspace))| AppendedContainer {
+                id: i as u64,
+                layout,
+                workspace: *workspace,
+                adopted: false,
+            })
+            .collect()
+    }
+
+    fn linked_commands<'a>(linked: &[Option<&'a Process>]) -> Vec<Option<&'a str>> {
+        linked
+            .iter()
+            .map(|process| process.map(|process| process.command[0].as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn link_processes_with_identical_criteria() {
+        let layouts = [
+            (placeholder("kitty"), Some("1")),
+            (placeholder("kitty"), Some("2")),
+            (placeholder("kitty"), Some("1")),
+            (placeholder("kitty"), Some("1")),
+            (placeholder("firefox"), Some("1")),
+        ];
+        let processes = [
+            Process::for_window("kitty 2", Some("2"), Some("kitty")),
+            Process::for_window("kitty 1a", Some("1"), Some("kitty")),
+            Process::for_window("kitty 1b", Some("1"), Some("kitty")),
+            Process::for_window("firefox", Some("1"), Some("firefox")),
+        ];
+
+        // Each process is linked once, the extra placeholder of workspace 1 gets none
+        assert_eq!(
+            linked_commands(&link_processes(&appended(&layouts), &processes)),
+            [
+                Some("kitty 1a"),
+                Some("kitty 2"),
+                Some("kitty 1b"),
+                None,
+                Some("firefox"),
+            ]
+        );
+    }
+
+    #[test]
+    fn link_processes_of_the_scratchpad() {
+        let layouts = [
+            (placeholder("kitty"), None),
+            (placeholder("kitty"), Some("1")),
+            (placeholder("kitty"), None),
+            (json!({ "type": "con" }), None),
+        ];
+        let processes = [
+            Process::for_window("kitty 1", Some("1"), Some("kitty")),
+            Process::for_window("scratch kitty", None, Some("kitty")),
+        ];
+
+        assert_eq!(
+            linked_commands(&link_processes(&appended(&layouts), &processes)),
+            [Some("scratch kitty"), Some("kitty 1"), None, None]
+        );
+    }
+}
@@ -0,0 +1,1020 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use chrono::Local;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::data_dir;
+use crate::error::{Error, Result};
+use crate::i3_tree::{
+    find_scratchpad, find_workspaces, get_all_windows, save_scratchpad, save_workspaces,
+    WorkspaceLayout,
+};
+use crate::i3ipc::{get_tree, get_version};
+use crate::metadata::{save_metadata, Metadata};
+use crate::output::{get_output_infos, OutputInfo};
+use crate::process::{save_processes, Process};
+
+/// The session used when no name is given.
+pub const DEFAULT_SESSION: &str = "default";
+
+/// The version of the session file format written by this build.
+pub const SESSION_FORMAT_VERSION: u32 = 1;
+
+const MAX_BACKUPS: usize = 1000;
+
+// Daemon saves are frequent, so they are backed up and rotated apart from the manual ones
+const MAX_AUTO_BACKUPS: usize = 100;
+const AUTO_BACKUP_SUFFIX: &str = "-auto";
+
+const SESSION_FILE: &str = "session.json";
+
+// Prefix of the directories in which files are prepared before being moved into place
+const STAGING_PREFIX: &str = ".staging-";
+
+// Before the single session file, a session was spread across these entries
+const LEGACY_ENTRIES: &[&str] = &["layouts", "processes.json", "metadata.json"];
+
+/// A named, independently saved layout, stored in `$XDG_DATA_HOME/i3-revive/sessions/<name>`.
+#[derive(Debug, Clone)]
+pub struct Session {
+    name: String,
+    dir: PathBuf,
+}
+
+/// Everything saved in a session, as stored in its session file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionData {
+    pub version: u32,
+    #[serde(default)]
+    pub provenance: Provenance,
+    #[serde(default)]
+    pub workspaces: Vec<WorkspaceLayout>,
+    /// The floating containers of the scratchpad, in the same format as workspace layouts.
+    #[serde(default)]
+    pub scratchpad: Vec<Value>,
+    #[serde(default)]
+    pub processes: Vec<Process>,
+    #[serde(default)]
+    pub metadata: Metadata,
+}
+
+impl SessionData {
+    /// Keeps only the given workspaces, and the processes and metadata that belong to them.
+    pub fn select_workspaces(&self, names: &[String]) -> Result<SessionData> {
+        if let Some(missing) = names
+            .iter()
+            .find(|name| !self.workspaces.iter().any(|ws| &ws.name == *name))
+        {
+            return Err(Error::Session(format!(
+                "No workspace {} was saved",
+                missing
+            )));
+        }
+
+        let is_selected = |name: &String| names.contains(name);
+
+        let unlinked = count_unlinked(&self.processes);
+        if unlinked > 0 {
+            eprintln!(
+                "Warning: Leaving out {} processes saved without their workspace",
+                unlinked
+            );
+        }
+
+        Ok(SessionData {
+            version: self.version,
+            provenance: self.provenance.clone(),
+            workspaces: self
+                .workspaces
+                .iter()
+                .filter(|ws| is_selected(&ws.name))
+                .cloned()
+                .collect(),
+            scratchpad: vec![],
+            processes: self
+                .processes
+                .iter()
+                .filter(|process| process.workspace.as_ref().is_some_and(is_selected))
+                .cloned()
+                .collect(),
+            metadata: Metadata {
+                visible_workspaces: self
+                    .metadata
+                    .visible_workspaces
+                    .iter()
+                    .filter(|name| is_selected(name))
+                    .cloned()
+                    .collect(),
+                workspace_outputs: self
+                    .metadata
+                    .workspace_outputs
+                    .iter()
+                    .filter(|(name, _)| is_selected(name))
+                    .map(|(name, output)| (name.clone(), output.clone()))
+                    .collect(),
+            },
+        })
+    }
+
+    /// Replaces the workspaces saved in `partial`, and the processes on them, leaving the others
+    /// as they were.
+    ///
+    /// The visible ones among them replace the workspaces visible on the same outputs, and the
+    /// last of them gets focused when restoring.
+    ///
+    /// Processes saved without their workspace are kept, as there's no telling whether they were
+    /// on the replaced workspaces.
+    pub fn merge_workspaces(&mut self, partial: SessionData) {
+        let names = partial
+            .workspaces
+            .iter()
+            .map(|ws| ws.name.clone())
+            .collect::<Vec<_>>();
+
+        self.workspaces.retain(|ws| !names.contains(&ws.name));
+        self.workspaces.extend(partial.workspaces);
+        self.processes.retain(|process| {
+            process
+                .workspace
+                .as_ref()
+                .is_none_or(|ws| !names.contains(ws))
+        });
+        let unlinked = count_unlinked(&self.processes);
+        if unlinked > 0 {
+            eprintln!(
+                "Warning: Keeping {} processes saved without their workspace, which may be \
+                 restored twice along with those just saved; save all workspaces to replace them",
+                unlinked
+            );
+        }
+        self.processes.extend(partial.processes);
+        let new_outputs = partial
+            .metadata
+            .visible_workspaces
+            .iter()
+            .filter_map(|name| partial.metadata.workspace_outputs.get(name))
+            .collect::<Vec<_>>();
+        let old_outputs = &self.metadata.workspace_outputs;
+        self.metadata.visible_workspaces.retain(|name| {
+            !names.contains(name)
+                && old_outputs
+                    .get(name)
+                    .is_none_or(|output| !new_outputs.contains(&output))
+        });
+        self.metadata
+            .visible_workspaces
+            .extend(partial.metadata.visible_workspaces);
+        for name in &names {
+            match partial.metadata.workspace_outputs.get(name) {
+                Some(output) => self
+                    .metadata
+                    .workspace_outputs
+                    .insert(name.clone(), output.clone()),
+                None => self.metadata.workspace_outputs.remove(name),
+            };
+        }
+
+        // Outputs that are gone may still be needed to restore the other workspaces
+        let mut outputs = partial.provenance.outputs;
+        for output in self.provenance.outputs.drain(..) {
+            if !outputs.iter().any(|o| o.name == output.name) {
+                outputs.push(output);
+            }
+        }
+        self.provenance = Provenance {
+            outputs,
+            ..partial.provenance
+        };
+        self.version = SESSION_FORMAT_VERSION;
+    }
+}
+
+// Processes saved before they were linked to their workspace, which can't be told apart from
+// one another
+fn count_unlinked(processes: &[Process]) -> usize {
+    processes
+        .iter()
+        .filter(|process| process.workspace.is_none() && process.window.is_none())
+        .count()
+}
+
+/// Where and when a session was saved.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Provenance {
+    pub i3_version: Option<String>,
+    pub hostname: Option<String>,
+    /// RFC 3339 timestamp of the save.
+    pub timestamp: Option<String>,
+    #[serde(default)]
+    pub outputs: Vec<OutputInfo>,
+}
+
+impl Session {
+    pub fn open(name: &str) -> Result<Session> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(Error::Session(format!("Invalid session name: {:?}", name)));
+        }
+
+        let sessions_dir = get_sessions_dir()?;
+        if name == DEFAULT_SESSION {
+            migrate_legacy_session(&data_dir()?, &sessions_dir)?;
+        }
+
+        Ok(Session {
+            name: name.to_string(),
+            dir: sessions_dir.join(name),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn session_path(&self) -> PathBuf {
+        self.dir.join(SESSION_FILE)
+    }
+
+    pub fn backups_dir(&self) -> PathBuf {
+        self.dir.join("backups")
+    }
+
+    /// Whether anything has been saved to this session.
+    pub fn exists(&self) -> bool {
+        self.session_path().exists() || self.has_legacy_entries()
+    }
+
+    fn has_legacy_entries(&self) -> bool {
+        LEGACY_ENTRIES
+            .iter()
+            .any(|entry| self.dir.join(entry).exists())
+    }
+
+    /// Reads the saved session, converting it from the legacy layout if needed.
+    pub fn load(&self) -> Result<SessionData> {
+        let path = self.session_path();
+        if !path.exists() {
+            if self.has_legacy_entries() {
+                return self.load_legacy();
+            }
+
+            return Err(Error::Session(format!(
+                "Session {} has not been saved",
+                self.name
+            )));
+        }
+
+        let content = fs::read_to_string(&path)?;
+        let version = serde_json::from_str::<Value>(&content)
+            .map_err(|e| Error::Session(format!("{}: {}", path.display(), e)))?
+            .get("version")
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| Error::Session(format!("{}: missing version", path.display())))?;
+        if version > SESSION_FORMAT_VERSION as u64 {
+            return Err(Error::Session(format!(
+                "{}: format version {} is newer than the supported version {}",
+                path.display(),
+                version,
+                SESSION_FORMAT_VERSION
+            )));
+        }
+
+        serde_json::from_str(&content)
+            .map_err(|e| Error::Session(format!("{}: {}", path.display(), e)))
+    }
+
+    // Per-workspace `ws_<name>.json` files of concatenated containers, plus
+    // `processes.json` and `metadata.json`
+    fn load_legacy(&self) -> Result<SessionData> {
+        let mut workspaces = vec![];
+        let layouts_dir = self.dir.join("layouts");
+        if layouts_dir.exists() {
+            let ws_name_re = Regex::new(r"^ws_(.+).json$").unwrap();
+            for entry in fs::read_dir(layouts_dir)? {
+                let entry = entry?;
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                let Some(caps) = ws_name_re.captures(&file_name) else {
+                    continue;
+                };
+
+                let content = fs::read_to_string(entry.path())?;
+                let nodes = serde_json::Deserializer::from_str(&content)
+                    .into_iter::<Value>()
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(|e| Error::Session(format!("{}: {}", file_name, e)))?;
+                workspaces.push(WorkspaceLayout {
+                    name: caps[1].to_string(),
+                    nodes,
+                    focus_path: vec![],
+                });
+            }
+        }
+        workspaces.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(SessionData {
+            version: SESSION_FORMAT_VERSION,
+            provenance: Provenance::default(),
+            workspaces,
+            scratchpad: vec![],
+            processes: read_legacy_file(&self.dir.join("processes.json"))?.unwrap_or_default(),
+            metadata: read_legacy_file(&self.dir.join("metadata.json"))?.unwrap_or_default(),
+        })
+    }
+
+    /// Writes the session file, replacing whatever was saved before.
+    ///
+    /// The file is written to a staging directory and then renamed over the previous one, so an
+    /// interrupted or failed save leaves the previous session untouched.
+    pub fn save(&self, data: &SessionData) -> Result<()> {
+        let json = serde_json::to_string_pretty(data).map_err(|e| Error::Session(e.to_string()))?;
+
+        fs::create_dir_all(&self.dir)?;
+        remove_stale_staging_dirs(&self.dir)?;
+
+        let staging_dir = self
+            .dir
+            .join(format!("{}{}", STAGING_PREFIX, process::id()));
+        let write_staged = || -> Result<()> {
+            fs::create_dir(&staging_dir)?;
+            let staged_path = staging_dir.join(SESSION_FILE);
+            let mut file = File::create(&staged_path)?;
+            file.write_all(json.as_bytes())?;
+            file.sync_all()?;
+
+            fs::rename(&staged_path, self.session_path())?;
+            File::open(&self.dir)?.sync_all()?;
+            Ok(())
+        };
+        let res = write_staged();
+
+        if let Err(e) = fs::remove_dir_all(&staging_dir) {
+            eprintln!(
+                "Warning: Failed to remove staging directory {:?}: {}",
+                staging_dir, e
+            );
+        }
+        res?;
+
+        // Only drop the legacy entries once the session file has fully replaced them
+        self.remove_legacy_entries()
+    }
+
+    fn remove_legacy_entries(&self) -> Result<()> {
+        for entry in LEGACY_ENTRIES {
+            let path = self.dir.join(entry);
+            if path.is_dir() {
+                fs::remove_dir_all(path)?;
+            } else if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the backups of this session, newest first.
+    pub fn backups(&self) -> Result<Vec<Backup>> {
+        let backups_dir = self.backups_dir();
+        if !backups_dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut backups = vec![];
+        for entry in fs::read_dir(backups_dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            if let Ok(dir_name) = entry.file_name().into_string() {
+                if dir_name.starts_with(STAGING_PREFIX) {
+                    continue;
+                }
+
+                let (timestamp, auto) = match dir_name.strip_suffix(AUTO_BACKUP_SUFFIX) {
+                    Some(timestamp) => (timestamp.to_string(), true),
+                    None => (dir_name, false),
+                };
+                backups.push(Backup {
+                    session: Session {
+                        name: format!("{}@{}", self.name, timestamp),
+                        dir: entry.path(),
+                    },
+                    timestamp,
+                    auto,
+                });
+            }
+        }
+
+        // Timestamps are zero-padded, so sorting them by name sorts them by time
+        backups.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+        Ok(backups)
+    }
+
+    /// Finds a backup by its timestamp, or by its 1-based position in [`Session::backups`].
+    pub fn find_backup(&self, selector: &str) -> Result<Backup> {
+        let mut backups = self.backups()?;
+        let position = match selector.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= backups.len() => Some(n - 1),
+            _ => backups.iter().position(|b| b.timestamp == selector),
+        };
+
+        position.map(|i| backups.swap_remove(i)).ok_or_else(|| {
+            Error::Session(format!("Session {} has no backup {}", self.name, selector))
+        })
+    }
+}
+
+/// A timestamped copy of a session, made before it was overwritten or removed.
+///
+/// Backups share the layout of a session, so they can be restored like one.
+#[derive(Debug, Clone)]
+pub struct Backup {
+    pub timestamp: String,
+    /// Whether the daemon made it, rather than a manual save or removal.
+    pub auto: bool,
+    pub session: Session,
+}
+
+fn read_legacy_file<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let content = fs::read_to_string(path)?;
+    serde_json::from_str(&content)
+        .map(Some)
+        .map_err(|e| Error::Session(format!("{}: {}", path.display(), e)))
+}
+
+/// Captures the current state of i3, ready to be saved with [`Session::save`].
+///
+/// If workspace names are given, only these workspaces are captured, without the scratchpad, to
+/// be merged into the saved session with [`SessionData::merge_workspaces`].
+pub fn capture_session(stream: &mut UnixStream, workspace_names: &[String]) -> Result<SessionData> {
+    let root = get_tree(stream)?;
+
+    let (scratchpad, scratchpad_windows) = match find_scratchpad(&root) {
+        Some(scratchpad) if workspace_names.is_empty() => (
+            save_scratchpad(scratchpad)?,
+            get_all_windows(std::slice::from_ref(scratchpad)),
+        ),
+        _ => (vec![], vec![]),
+    };
+    let mut workspaces = find_workspaces(root);
+    if !workspace_names.is_empty() {
+        if let Some(missing) = workspace_names
+            .iter()
+            .find(|name| !workspaces.iter().any(|ws| ws.name.as_ref() == Some(*name)))
+        {
+            return Err(Error::Session(format!("No workspace {} is open", missing)));
+        }
+        workspaces.retain(|ws| {
+            ws.name
+                .as_ref()
+                .is_some_and(|name| workspace_names.contains(name))
+        });
+    }
+    let mut windows = get_all_windows(&workspaces);
+    windows.extend(scratchpad_windows);
+
+    Ok(SessionData {
+        version: SESSION_FORMAT_VERSION,
+        provenance: save_provenance(stream)?,
+        workspaces: save_workspaces(&workspaces)?,
+        scratchpad,
+        processes: save_processes(windows)?,
+        metadata: save_metadata(stream)?,
+    })
+}
+
+/// Records the i3 version, hostname, time and outputs the session is being saved with.
+pub fn save_provenance(stream: &mut UnixStream) -> Result<Provenance> {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|hostname| hostname.trim().to_string());
+
+    Ok(Provenance {
+        i3_version: Some(get_version(stream)?.human_readable),
+        hostname,
+        timestamp: Some(Local::now().to_rfc3339()),
+        outputs: get_output_infos(stream)?,
+    })
+}
+
+fn get_sessions_dir() -> Result<PathBuf> {
+    Ok(data_dir()?.join("sessions"))
+}
+
+// Before named sessions, the only session lived directly in the data directory, `legacy_dir`
+fn migrate_legacy_session(legacy_dir: &Path, sessions_dir: &Path) -> Result<()> {
+    let default_dir = sessions_dir.join(DEFAULT_SESSION);
+    if default_dir.exists() {
+        return Ok(());
+    }
+
+    let legacy_entries = LEGACY_ENTRIES
+        .iter()
+        .chain(["backups"].iter())
+        .map(|entry| (legacy_dir.join(entry), default_dir.join(entry)))
+        .filter(|(legacy, _)| legacy.exists())
+        .collect::<Vec<_>>();
+    if legacy_entries.is_empty() {
+        return Ok(());
+    }
+
+    fs::create_dir_all(&default_dir)?;
+    for (legacy, migrated) in legacy_entries {
+        fs::rename(legacy, migrated)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the names of all sessions that have something saved, sorted by name.
+pub fn list_sessions() -> Result<Vec<String>> {
+    let sessions_dir = get_sessions_dir()?;
+    migrate_legacy_session(&data_dir()?, &sessions_dir)?;
+    if !sessions_dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut names = vec![];
+    for entry in fs::read_dir(&sessions_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        if let Ok(name) = entry.file_name().into_string() {
+            if Session::open(&name)?.exists() {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+// Leftovers of saves that were interrupted before they could clean up. Staging directories are
+// named after the pid of their writer, and those of other running processes are still being
+// written.
+fn remove_stale_staging_dirs(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(suffix) = file_name
+            .to_string_lossy()
+            .strip_prefix(STAGING_PREFIX)
+            .map(String::from)
+        else {
+            continue;
+        };
+
+        let writer_pid = suffix
+            .split('-')
+            .next()
+            .and_then(|pid| pid.parse::<u32>().ok());
+        // Our own leftovers can only come from an earlier save that failed to clean up
+        let is_live_writer = writer_pid.is_some_and(|pid| {
+            pid != process::id() && Path::new(&format!("/proc/{}", pid)).exists()
+        });
+        if is_live_writer {
+            continue;
+        }
+        // Another save may be removing it too
+        match fs::remove_dir_all(entry.path()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the session into a new timestamped backup, leaving the session itself untouched.
+pub fn backup(session: &Session) -> Result<()> {
+    backup_as(session, false)
+}
+
+/// Like [`backup`], for the saves of the daemon. Only the most recent of these are kept, without
+/// counting against the backups of manual saves.
+pub fn auto_backup(session: &Session) -> Result<()> {
+    backup_as(session, true)
+}
+
+fn backup_as(session: &Session, auto: bool) -> Result<()> {
+    // Nothing to back up, and an empty backup would only clutter the list
+    if !session.exists() {
+        return Ok(());
+    }
+
+    let suffix = if auto { AUTO_BACKUP_SUFFIX } else { "" };
+    let timestamp = format!("{}{}", Local::now().format("%Y_%m_%d_%H_%M_%S_%3f"), suffix);
+    let backups_dir = session.backups_dir();
+    let backup_dir = backups_dir.join(&timestamp);
+
+    fs::create_dir_all(&backups_dir)?;
+    remove_stale_staging_dirs(&backups_dir)?;
+
+    // Build the backup aside, so a partial copy never shows up as a backup
+    let staging_dir =
+        backups_dir.join(format!("{}{}-{}", STAGING_PREFIX, process::id(), timestamp));
+    fs::create_dir(&staging_dir)?;
+
+    // Copy the session file, or the legacy entries of a session that predates it
+    for entry in [SESSION_FILE].iter().chain(LEGACY_ENTRIES) {
+        let source = session.dir().join(entry);
+        let dest = staging_dir.join(entry);
+        if source.is_dir() {
+            fs::create_dir_all(&dest)?;
+            for file in fs::read_dir(source)? {
+                let file = file?;
+                fs::copy(file.path(), dest.join(file.file_name()))?;
+            }
+        } else if source.exists() {
+            fs::copy(source, dest)?;
+        }
+    }
+    fs::rename(&staging_dir, &backup_dir)?;
+
+    // Clean up old backups of the same kind - keep only the most recent ones
+    let mut entries = Vec::new();
+
+    for entry in fs::read_dir(&backups_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir()
+            && !file_name.starts_with(STAGING_PREFIX)
+            && file_name.ends_with(AUTO_BACKUP_SUFFIX) == auto
+        {
+            entries.push(entry.path());
+        }
+    }
+
+    // Sort by modification time (newest first)
+    entries.sort_by_cached_key(|path| {
+        std::cmp::Reverse(fs::metadata(path).and_then(|m| m.modified()).ok())
+    });
+
+    // Remove old backups beyond the limit
+    let max_backups = if auto { MAX_AUTO_BACKUPS } else { MAX_BACKUPS };
+    for old_backup in entries.iter().skip(max_backups) {
+        if let Err(e) = fs::remove_dir_all(old_backup) {
+            eprintln!(
+                "Warning: Failed to remove old backup {:?}: {}",
+                old_backup, e
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Backs up the session, then removes it.
+pub fn backup_and_clear(session: &Session) -> Result<()> {
+    backup(session)?;
+
+    // Remove existing data
+    let session_path = session.session_path();
+    if session_path.exists() {
+        fs::remove_file(session_path)?;
+    }
+    session.remove_legacy_entries()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use std::env;
+
+    fn layout(name: &str) -> WorkspaceLayout {
+        WorkspaceLayout {
+            name: name.to_string(),
+            nodes: vec![json!({ "name": name })],
+            focus_path: vec![],
+        }
+    }
+
+    fn session_data(
+        workspaces: &[&str],
+        processes: Vec<Process>,
+        visible: &[&str],
+        outputs: &[(&str, &str)],
+    ) -> SessionData {
+        SessionData {
+            version: SESSION_FORMAT_VERSION,
+            provenance: Provenance::default(),
+            workspaces: workspaces.iter().map(|name| layout(name)).collect(),
+            scratchpad: vec![json!({ "name": "scratch" })],
+            processes,
+            metadata: Metadata {
+                visible_workspaces: visible.iter().map(|name| name.to_string()).collect(),
+                workspace_outputs: outputs
+                    .iter()
+                    .map(|(ws, output)| (ws.to_string(), output.to_string()))
+                    .collect(),
+            },
+        }
+    }
+
+    // An empty directory of its own for each test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("i3-revive-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_session(dir: &Path) -> Session {
+        Session {
+            name: DEFAULT_SESSION.to_string(),
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn names(strs: &[&str]) -> Vec<String> {
+        strs.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn commands(data: &SessionData) -> Vec<&str> {
+        data.processes
+            .iter()
+            .map(|process| process.command[0].as_str())
+            .collect()
+    }
+
+    fn workspace_names(data: &SessionData) -> Vec<&str> {
+        data.workspaces.iter().map(|ws| ws.name.as_str()).collect()
+    }
+
+    #[test]
+    fn select_workspaces_keeps_what_belongs_to_them() {
+        let data = session_data(
+            &["1", "2", "3"],
+            vec![
+                Process::for_window("one", Some("1"), Some("app")),
+                Process::for_window("two", Some("2"), Some("app")),
+                Process::for_window("three", Some("3"), Some("app")),
+                Process::for_window("scratch", None, Some("app")),
+                Process::for_window("legacy", None, None),
+            ],
+            &["1", "3"],
+            &[("1", "DP-1"), ("2", "DP-1"), ("3", "HDMI-1")],
+        );
+
+        let selected = data.select_workspaces(&names(&["3", "2"])).unwrap();
+        assert_eq!(workspace_names(&selected), ["2", "3"]);
+        assert!(selected.scratchpad.is_empty());
+        assert_eq!(commands(&selected), ["two", "three"]);
+        assert_eq!(selected.metadata.visible_workspaces, ["3"]);
+        assert_eq!(
+            selected.metadata.workspace_outputs,
+            BTreeMap::from([
+                ("2".to_string(), "DP-1".to_string()),
+                ("3".to_string(), "HDMI-1".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn select_workspaces_rejects_unsaved_workspaces() {
+        let data = session_data(&["1"], vec![], &[], &[]);
+        assert!(matches!(
+            data.select_workspaces(&names(&["1", "mail"])),
+            Err(Error::Session(_))
+        ));
+    }
+
+    #[test]
+    fn merge_workspaces_replaces_saved_workspaces_only() {
+        let mut saved = session_data(
+            &["1", "2", "3"],
+            vec![
+                Process::for_window("one", Some("1"), Some("app")),
+                Process::for_window("two", Some("2"), Some("app")),
+                Process::for_window("scratch", None, Some("app")),
+            ],
+            &["1", "3"],
+            &[("1", "DP-1"), ("2", "DP-1"), ("3", "HDMI-1")],
+        );
+        saved.provenance.outputs = vec![OutputInfo {
+            name: "HDMI-1".to_string(),
+            ..Default::default()
+        }];
+        let mut partial = session_data(
+            &["2", "4"],
+            vec![
+                Process::for_window("new two", Some("2"), Some("app")),
+                Process::for_window("four", Some("4"), Some("app")),
+            ],
+            &["2"],
+            &[("2", "DP-1"), ("4", "HDMI-1")],
+        );
+        partial.scratchpad = vec![];
+        partial.provenance.outputs = vec![OutputInfo {
+            name: "DP-1".to_string(),
+            ..Default::default()
+        }];
+
+        saved.merge_workspaces(partial);
+        assert_eq!(workspace_names(&saved), ["1", "3", "2", "4"]);
+        assert_eq!(saved.scratchpad.len(), 1);
+        assert_eq!(commands(&saved), ["one", "scratch", "new two", "four"]);
+        // 2 is now the visible workspace of DP-1, instead of 1
+        assert_eq!(saved.metadata.visible_workspaces, ["3", "2"]);
+        assert_eq!(saved.metadata.workspace_outputs["4"], "HDMI-1");
+        let outputs = saved
+            .provenance
+            .outputs
+            .iter()
+            .map(|output| output.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, ["DP-1", "HDMI-1"]);
+    }
+
+    #[test]
+    fn merge_workspaces_keeps_unlinked_processes() {
+        let mut saved = session_data(
+            &["1", "code"],
+            vec![
+                Process::for_window("legacy term", None, None),
+                Process::for_window("legacy code", None, None),
+            ],
+            &[],
+            &[],
+        );
+        let partial = session_data(
+            &["code"],
+            vec![Process::for_window("code", Some("code"), Some("app"))],
+            &[],
+            &[],
+        );
+
+        saved.merge_workspaces(partial);
+        assert_eq!(workspace_names(&saved), ["1", "code"]);
+        assert_eq!(commands(&saved), ["legacy term", "legacy code", "code"]);
+        assert_eq!(count_unlinked(&saved.processes), 2);
+    }
+
+    #[test]
+    fn merge_workspaces_drops_workspaces_no_longer_visible() {
+        let mut saved = session_data(&["1", "2"], vec![], &["2", "1"], &[]);
+        let partial = session_data(&["1"], vec![], &[], &[("1", "DP-1")]);
+
+        saved.merge_workspaces(partial);
+        assert_eq!(saved.metadata.visible_workspaces, ["2"]);
+    }
+
+    #[test]
+    fn load_checks_the_format_version() {
+        let dir = test_dir("load_version");
+        let session = test_session(&dir);
+        assert!(matches!(session.load(), Err(Error::Session(_))));
+
+        let data = session_data(
+            &["1"],
+            vec![Process::for_window("one", Some("1"), Some("app"))],
+            &["1"],
+            &[],
+        );
+        session.save(&data).unwrap();
+        let loaded = session.load().unwrap();
+        assert_eq!(workspace_names(&loaded), ["1"]);
+        assert_eq!(commands(&loaded), ["one"]);
+
+        for (content, error) in [
+            (json!({ "version": SESSION_FORMAT_VERSION + 1 }), "is newer"),
+            (json!({ "workspaces": [] }), "missing version"),
+        ] {
+            fs::write(session.session_path(), content.to_string()).unwrap();
+            match session.load() {
+                Err(Error::Session(e)) => assert!(e.contains(error), "{}", e),
+                res => panic!("expected {:?}, got {:?}", error, res),
+            }
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn load_converts_legacy_sessions() {
+        let dir = test_dir("load_legacy");
+        let layouts_dir = dir.join("layouts");
+        fs::create_dir(&layouts_dir).unwrap();
+        fs::write(
+            layouts_dir.join("ws_2: mail.json"),
+            "{\"name\": \"a\"}\n{\"name\": \"b\"}",
+        )
+        .unwrap();
+        fs::write(layouts_dir.join("ws_1.json"), "{\"name\": \"c\"}").unwrap();
+        fs::write(layouts_dir.join("notes.txt"), "not a layout").unwrap();
+        fs::write(
+            dir.join("processes.json"),
+            r#"[{"command": ["thunderbird"], "working_directory": "/home"}]"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("metadata.json"),
+            r#"{"visible_workspaces": ["2: mail"]}"#,
+        )
+        .unwrap();
+
+        let session = test_session(&dir);
+        assert!(session.exists());
+        let loaded = session.load().unwrap();
+        assert_eq!(loaded.version, SESSION_FORMAT_VERSION);
+        assert_eq!(workspace_names(&loaded), ["1", "2: mail"]);
+        assert_eq!(
+            loaded.workspaces[1].nodes,
+            [json!({ "name": "a" }), json!({ "name": "b" })]
+        );
+        assert_eq!(commands(&loaded), ["thunderbird"]);
+        assert_eq!(loaded.processes[0].workspace, None);
+        assert_eq!(loaded.metadata.visible_workspaces, ["2: mail"]);
+
+        // Saving replaces the legacy entries with the session file
+        session.save(&loaded).unwrap();
+        assert!(!layouts_dir.exists() && !dir.join("processes.json").exists());
+        assert_eq!(workspace_names(&session.load().unwrap()), ["1", "2: mail"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrate_legacy_session_moves_it_to_the_default_session() {
+        let legacy_dir = test_dir("migrate");
+        let sessions_dir = legacy_dir.join("sessions");
+        fs::create_dir_all(legacy_dir.join("layouts")).unwrap();
+        fs::write(legacy_dir.join("layouts/ws_1.json"), "{}").unwrap();
+        fs::write(legacy_dir.join("processes.json"), "[]").unwrap();
+        fs::create_dir_all(legacy_dir.join("backups/2024_01_01_00_00_00_000")).unwrap();
+
+        migrate_legacy_session(&legacy_dir, &sessions_dir).unwrap();
+        let default_dir = sessions_dir.join(DEFAULT_SESSION);
+        assert!(default_dir.join("layouts/ws_1.json").exists());
+        assert!(default_dir.join("processes.json").exists());
+        assert!(default_dir.join("backups/2024_01_01_00_00_00_000").exists());
+        assert!(!legacy_dir.join("layouts").exists());
+        assert!(!legacy_dir.join("backups").exists());
+
+        // An existing default session is never overwritten
+        fs::write(legacy_dir.join("metadata.json"), "{}").unwrap();
+        migrate_legacy_session(&legacy_dir, &sessions_dir).unwrap();
+        assert!(legacy_dir.join("metadata.json").exists());
+        assert!(!default_dir.join("metadata.json").exists());
+
+        fs::remove_dir_all(legacy_dir).unwrap();
+    }
+
+    #[test]
+    fn migrate_legacy_session_without_legacy_entries() {
+        let legacy_dir = test_dir("migrate_nothing");
+        let sessions_dir = legacy_dir.join("sessions");
+
+        migrate_legacy_session(&legacy_dir, &sessions_dir).unwrap();
+        assert!(!sessions_dir.exists());
+
+        fs::remove_dir_all(legacy_dir).unwrap();
+    }
+
+    #[test]
+    fn backups_tell_daemon_backups_apart() {
+        let dir = test_dir("backups");
+        let session = test_session(&dir);
+        backup(&session).unwrap();
+        assert!(session.backups().unwrap().is_empty());
+
+        session
+            .save(&session_data(&["1"], vec![], &[], &[]))
+            .unwrap();
+        backup(&session).unwrap();
+        auto_backup(&session).unwrap();
+
+        let mut backups = session.backups().unwrap();
+        backups.sort_by_key(|backup| backup.auto);
+        assert_eq!(
+            backups.iter().map(|backup| backup.auto).collect::<Vec<_>>(),
+            [false, true]
+        );
+        for backup in &backups {
+            assert_eq!(backup.timestamp.len(), "2024_01_01_00_00_00_000".len());
+            assert_eq!(workspace_names(&backup.session.load().unwrap()), ["1"]);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
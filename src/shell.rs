@@ -0,0 +1,317 @@
+use shlex::try_quote;
+
+use crate::error::{Error, Result};
+
+/// Marks the command line of shells started by a revived terminal, so that their arguments
+/// don't prevent reviving them again.
+pub const REVIVE_MARK: &str = "Revive-Terminal-Mark";
+
+/// A family of shells sharing the same syntax, which terminal windows are revived with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    /// sh, bash, zsh, ksh, dash and other POSIX shells.
+    Posix,
+    Fish,
+    /// tcsh and csh.
+    Tcsh,
+    Xonsh,
+    /// nushell.
+    Nu,
+    Elvish,
+}
+
+impl Shell {
+    /// Recognizes a shell from the name of its executable.
+    pub fn from_name(name: &str) -> Option<Shell> {
+        match name {
+            "sh" | "bash" | "zsh" | "ksh" | "ksh93" | "mksh" | "oksh" | "dash" => {
+                Some(Shell::Posix)
+            }
+            "fish" => Some(Shell::Fish),
+            "tcsh" | "csh" => Some(Shell::Tcsh),
+            "xonsh" => Some(Shell::Xonsh),
+            "nu" => Some(Shell::Nu),
+            "elvish" => Some(Shell::Elvish),
+            _ => None,
+        }
+    }
+
+    /// Quotes a word so that the shell reads it back as is.
+    pub fn quote(self, word: &str) -> Result<String> {
+        if word.contains('\0') {
+            return Err(Error::Proc(format!("Cannot quote {:?}", word)));
+        }
+
+        Ok(match self {
+            Shell::Posix => try_quote(word)
+                .map_err(|e| Error::Proc(e.to_string()))?
+                .into_owned(),
+            Shell::Fish => format!("'{}'", word.replace('\\', "\\\\").replace('\'', "\\'")),
+            // History substitution happens even inside single quotes
+            Shell::Tcsh => format!(
+                "'{}'",
+                word.replace('\'', "'\\''")
+                    .replace('!', "\\!")
+                    .replace('\n', "\\\n")
+            ),
+            // Python string literals
+            Shell::Xonsh => format!(
+                "'{}'",
+                word.replace('\\', "\\\\")
+                    .replace('\'', "\\'")
+                    .replace('\n', "\\n")
+            ),
+            Shell::Nu => format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\"")),
+            Shell::Elvish => format!("'{}'", word.replace('\'', "''")),
+        })
+    }
+
+    /// Returns the line running a command.
+    pub fn command_line<'a>(self, cmd: impl IntoIterator<Item = &'a str>) -> Result<String> {
+        let mut cmd = cmd.into_iter();
+        let head = match (self, cmd.next()) {
+            (_, None) => return Ok(String::new()),
+            // Builtins such as printf would shadow the external command otherwise, and only
+            // bare words can be namespaced
+            (Shell::Elvish, Some(head))
+                if !head.is_empty()
+                    && head
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "_-.+".contains(c)) =>
+            {
+                format!("e:{}", head)
+            }
+            (_, Some(head)) => self.quote(head)?,
+        };
+        let words = [Ok(head)]
+            .into_iter()
+            .chain(cmd.map(|word| self.quote(word)))
+            .collect::<Result<Vec<_>>>()?
+            .join(" ");
+
+        Ok(match self {
+            // A line of quoted words is a Python expression otherwise
+            Shell::Xonsh => format!("![{}]", words),
+            // A quoted head is a string rather than a command otherwise
+            Shell::Nu => format!("^{}", words),
+            _ => words,
+        })
+    }
+
+    /// Returns the line replacing the shell with a command.
+    pub fn exec_line<'a>(self, cmd: impl IntoIterator<Item = &'a str>) -> Result<String> {
+        let words = cmd
+            .into_iter()
+            .map(|word| self.quote(word))
+            .collect::<Result<Vec<_>>>()?
+            .join(" ");
+
+        Ok(match self {
+            // exec is an alias, which must not be quoted
+            Shell::Xonsh => format!("![exec {}]", words),
+            _ => format!("exec {}", words),
+        })
+    }
+
+    /// Returns the arguments making the shell run `line`, if any, as if it was typed in, and
+    /// then stay interactive.
+    pub fn interactive_args(self, shell_cmd: &str, line: Option<&str>) -> Result<Vec<String>> {
+        let exec_shell = Some(self.exec_line([shell_cmd])?);
+        let line = line.map(String::from);
+        let (flags, mark, line, exec_shell): (&[&str], _, _, _) = match self {
+            Shell::Posix | Shell::Fish => (
+                &["-i", "-c"],
+                format!("true {}", REVIVE_MARK),
+                line,
+                exec_shell,
+            ),
+            // tcsh reads its rc file anyway, and would prompt with -i
+            Shell::Tcsh => (&["-c"], format!("true {}", REVIVE_MARK), line, exec_shell),
+            Shell::Xonsh => (
+                &["-i", "-c"],
+                format!("![true {}]", REVIVE_MARK),
+                line,
+                exec_shell,
+            ),
+            // nushell stays interactive after running the commands given to -e
+            Shell::Nu => (&["-e"], format!("\"{}\" | ignore", REVIVE_MARK), line, None),
+            // A failing command throws, which would skip the exec
+            Shell::Elvish => (
+                &["-c"],
+                format!("nop {}", REVIVE_MARK),
+                line.map(|line| format!("try {{ {} }} catch {{ }}", line)),
+                exec_shell,
+            ),
+        };
+
+        let script = [Some(mark), line, exec_shell]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        Ok(flags
+            .iter()
+            .map(|flag| flag.to_string())
+            .chain([script])
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    const ALL: [Shell; 6] = [
+        Shell::Posix,
+        Shell::Fish,
+        Shell::Tcsh,
+        Shell::Xonsh,
+        Shell::Nu,
+        Shell::Elvish,
+    ];
+    const TRICKY: &str = "it's a \"test\" \\ $HOME !x";
+
+    #[test]
+    fn from_name_groups_shells_by_syntax() {
+        for name in ["sh", "bash", "zsh", "ksh", "mksh", "dash"] {
+            assert_eq!(Shell::from_name(name), Some(Shell::Posix));
+        }
+        assert_eq!(Shell::from_name("fish"), Some(Shell::Fish));
+        assert_eq!(Shell::from_name("tcsh"), Some(Shell::Tcsh));
+        assert_eq!(Shell::from_name("csh"), Some(Shell::Tcsh));
+        assert_eq!(Shell::from_name("xonsh"), Some(Shell::Xonsh));
+        assert_eq!(Shell::from_name("nu"), Some(Shell::Nu));
+        assert_eq!(Shell::from_name("elvish"), Some(Shell::Elvish));
+        assert_eq!(Shell::from_name("python3"), None);
+    }
+
+    #[test]
+    fn quote_per_shell() {
+        let quoted = ALL.map(|shell| shell.quote(TRICKY).unwrap());
+        assert_eq!(
+            quoted,
+            [
+                r#""it's a \"test\" \\ "'$HOME !x'"#.to_string(),
+                r#"'it\'s a "test" \\ $HOME !x'"#.to_string(),
+                r#"'it'\''s a "test" \ $HOME \!x'"#.to_string(),
+                r#"'it\'s a "test" \\ $HOME !x'"#.to_string(),
+                r#""it's a \"test\" \\ $HOME !x""#.to_string(),
+                r#"'it''s a "test" \ $HOME !x'"#.to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn quote_rejects_nul() {
+        for shell in ALL {
+            assert!(shell.quote("a\0b").is_err());
+        }
+    }
+
+    #[test]
+    fn quote_round_trips_through_sh() {
+        let line = Shell::Posix.command_line(["printf", "%s", TRICKY]).unwrap();
+        let output = Command::new("sh").args(["-c", &line]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), TRICKY);
+    }
+
+    #[test]
+    fn command_line_per_shell() {
+        let lines = ALL.map(|shell| {
+            shell
+                .command_line(["printf", "\\033]0;%s\\007", "T 1"])
+                .unwrap()
+        });
+        assert_eq!(
+            lines,
+            [
+                r#"printf "\\033]0;%s\\007" 'T 1'"#.to_string(),
+                r#"'printf' '\\033]0;%s\\007' 'T 1'"#.to_string(),
+                r#"'printf' '\033]0;%s\007' 'T 1'"#.to_string(),
+                r#"!['printf' '\\033]0;%s\\007' 'T 1']"#.to_string(),
+                r#"^"printf" "\\033]0;%s\\007" "T 1""#.to_string(),
+                r#"e:printf '\033]0;%s\007' 'T 1'"#.to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn command_line_keeps_elvish_paths_quoted() {
+        assert_eq!(
+            Shell::Elvish.command_line(["/usr/bin/vim", "x"]).unwrap(),
+            "'/usr/bin/vim' 'x'"
+        );
+        assert_eq!(Shell::Elvish.command_line(["a b"]).unwrap(), "'a b'");
+    }
+
+    #[test]
+    fn exec_line_per_shell() {
+        assert_eq!(
+            Shell::Posix.exec_line(["/bin/zsh", "-i"]).unwrap(),
+            "exec /bin/zsh -i"
+        );
+        assert_eq!(
+            Shell::Xonsh.exec_line(["xonsh"]).unwrap(),
+            "![exec 'xonsh']"
+        );
+        assert_eq!(Shell::Nu.exec_line(["nu"]).unwrap(), "exec \"nu\"");
+    }
+
+    #[test]
+    fn interactive_args_per_shell() {
+        let args = |shell: Shell, line| shell.interactive_args("/bin/x", line).unwrap();
+
+        assert_eq!(
+            args(Shell::Posix, Some("vim")),
+            ["-i", "-c", "true Revive-Terminal-Mark; vim; exec /bin/x"]
+        );
+        assert_eq!(
+            args(Shell::Posix, None),
+            ["-i", "-c", "true Revive-Terminal-Mark; exec /bin/x"]
+        );
+        assert_eq!(
+            args(Shell::Fish, Some("vim")),
+            ["-i", "-c", "true Revive-Terminal-Mark; vim; exec '/bin/x'"]
+        );
+        assert_eq!(
+            args(Shell::Tcsh, Some("vim")),
+            ["-c", "true Revive-Terminal-Mark; vim; exec '/bin/x'"]
+        );
+        assert_eq!(
+            args(Shell::Xonsh, Some("![vim]")),
+            [
+                "-i",
+                "-c",
+                "![true Revive-Terminal-Mark]; ![vim]; ![exec '/bin/x']"
+            ]
+        );
+        assert_eq!(
+            args(Shell::Nu, Some("^vim")),
+            ["-e", "\"Revive-Terminal-Mark\" | ignore; ^vim"]
+        );
+        assert_eq!(
+            args(Shell::Elvish, Some("e:vim")),
+            [
+                "-c",
+                "nop Revive-Terminal-Mark; try { e:vim } catch { }; exec '/bin/x'"
+            ]
+        );
+        assert_eq!(
+            args(Shell::Elvish, None),
+            ["-c", "nop Revive-Terminal-Mark; exec '/bin/x'"]
+        );
+    }
+
+    #[test]
+    fn interactive_args_run_in_sh() {
+        let mut args = Shell::Posix
+            .interactive_args("true", Some("printf ok"))
+            .unwrap();
+        args.retain(|arg| arg != "-i");
+        let output = Command::new("sh").args(&args).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ok");
+    }
+}
@@ -0,0 +1,826 @@
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::process::Command;
+use std::ptr;
+
+use serde::Deserialize;
+use serde_json::Value;
+use shlex::try_quote;
+
+use crate::config::TerminalBackend;
+use crate::error::{Error, Result};
+use crate::process::{get_first_child_pid, get_process_cmd, get_process_env, get_shell_pid};
+
+/// A tab of a terminal window, with its panes in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TerminalTab {
+    /// The name of the layout arranging the panes, for terminals that have named layouts.
+    pub layout: Option<String>,
+    pub panes: Vec<TerminalPane>,
+}
+
+/// A process in the foreground of a terminal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForegroundProcess {
+    pub pid: u32,
+    pub command: Vec<String>,
+}
+
+/// A pane of a terminal tab, which is a whole tab unless the tab is split.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TerminalPane {
+    /// The working directory, empty if the terminal doesn't know it.
+    pub cwd: String,
+    /// The command running in the foreground, or `None` when the shell is idle.
+    pub foreground: Option<ForegroundProcess>,
+    /// The tty of the pane, for terminals that don't report the foreground command themselves.
+    pub tty: Option<String>,
+    /// Whether the pane is to the right of the previous one of the tab, rather than below it.
+    pub beside_previous: bool,
+}
+
+#[derive(Deserialize)]
+struct KittyOsWindow {
+    platform_window_id: Option<u64>,
+    tabs: Vec<KittyTab>,
+}
+
+#[derive(Deserialize)]
+struct KittyTab {
+    layout: Option<String>,
+    #[serde(default)]
+    layout_state: Value,
+    windows: Vec<KittyWindow>,
+}
+
+#[derive(Deserialize)]
+struct KittyWindow {
+    id: u64,
+    pid: u32,
+    cwd: String,
+    #[serde(default)]
+    foreground_processes: Vec<KittyProcess>,
+}
+
+#[derive(Deserialize)]
+struct KittyProcess {
+    pid: u32,
+    cmdline: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct WeztermPane {
+    window_id: u64,
+    tab_id: u64,
+    #[serde(default)]
+    window_title: Option<String>,
+    #[serde(default)]
+    cwd: String,
+    #[serde(default)]
+    tty_name: Option<String>,
+    #[serde(default)]
+    top_row: usize,
+}
+
+/// Reads the tabs and panes of a terminal window through the remote control of its terminal.
+pub fn capture_tabs(
+    backend: TerminalBackend,
+    terminal_pid: u32,
+    window_id: u32,
+    window_title: &str,
+) -> Result<Vec<TerminalTab>> {
+    match backend {
+        TerminalBackend::Kitty => {
+            // kitty only exports the socket it listens on to its children
+            let shell_pid = get_shell_pid(terminal_pid, window_id)?.ok_or_else(|| {
+                Error::Proc(format!(
+                    "Terminal with pid of {} has no shell",
+                    terminal_pid
+                ))
+            })?;
+            let listen_on = get_process_env(shell_pid)?
+                .remove("KITTY_LISTEN_ON")
+                .ok_or_else(|| {
+                    Error::Proc("kitty doesn't listen for remote control, set listen_on".into())
+                })?;
+
+            let output = run_backend(Command::new("kitty").args(["@", "--to", &listen_on, "ls"]))?;
+            parse_kitty_ls(&output, window_id)
+        }
+        TerminalBackend::Wezterm => {
+            let output =
+                run_backend(Command::new("wezterm").args(["cli", "list", "--format", "json"]))?;
+            let mut tabs = parse_wezterm_list(&output, window_title)?;
+            for pane in tabs.iter_mut().flat_map(|tab| tab.panes.iter_mut()) {
+                if let Some(tty) = &pane.tty {
+                    pane.foreground = get_tty_foreground(tty)?;
+                }
+            }
+            Ok(tabs)
+        }
+    }
+}
+
+fn run_backend(command: &mut Command) -> Result<String> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(Error::Proc(format!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    String::from_utf8(output.stdout).map_err(|e| Error::Proc(e.to_string()))
+}
+
+/// Parses the output of `kitty @ ls`, keeping the tabs of the OS window with the given X window
+/// id.
+pub fn parse_kitty_ls(json: &str, window_id: u32) -> Result<Vec<TerminalTab>> {
+    let os_windows: Vec<KittyOsWindow> = serde_json::from_str(json)
+        .map_err(|e| Error::Proc(format!("Invalid kitty @ ls output: {}", e)))?;
+    let os_window = os_windows
+        .into_iter()
+        .find(|os_window| os_window.platform_window_id == Some(window_id as u64))
+        .ok_or_else(|| Error::Proc(format!("kitty has no window {}", window_id)))?;
+
+    Ok(os_window
+        .tabs
+        .into_iter()
+        .map(|tab| {
+            let mut split_paths = HashMap::new();
+            collect_kitty_split_paths(&tab.layout_state["pairs"], &mut vec![], &mut split_paths);
+            let mut previous_id = None;
+
+            TerminalTab {
+                layout: tab.layout,
+                panes: tab
+                    .windows
+                    .into_iter()
+                    .map(|window| {
+                        let beside_previous = previous_id.is_some_and(|previous_id| {
+                            is_kitty_split_horizontal(&split_paths, previous_id, window.id)
+                        });
+                        previous_id = Some(window.id);
+
+                        TerminalPane {
+                            // The shell itself is in the foreground when it is idle
+                            foreground: window
+                                .foreground_processes
+                                .into_iter()
+                                .find(|process| process.pid != window.pid)
+                                .map(|process| ForegroundProcess {
+                                    pid: process.pid,
+                                    command: process.cmdline,
+                                }),
+                            cwd: window.cwd,
+                            tty: None,
+                            beside_previous,
+                        }
+                    })
+                    .collect(),
+            }
+        })
+        .collect())
+}
+
+// The splits layout of kitty is a tree of pairs of windows or pairs, each either side by side
+// (horizontal) or one above the other. Each window gets the pairs it is in, from the root down.
+fn collect_kitty_split_paths<'a>(
+    pair: &'a Value,
+    path: &mut Vec<&'a Value>,
+    paths: &mut HashMap<u64, Vec<&'a Value>>,
+) {
+    if !pair.is_object() {
+        return;
+    }
+
+    path.push(pair);
+    for child in [&pair["one"], &pair["two"]] {
+        match child.as_u64() {
+            Some(window_id) => {
+                paths.insert(window_id, path.clone());
+            }
+            None => collect_kitty_split_paths(child, path, paths),
+        }
+    }
+    path.pop();
+}
+
+// Whether the innermost pair holding both windows puts them side by side
+fn is_kitty_split_horizontal(
+    paths: &HashMap<u64, Vec<&Value>>,
+    first_id: u64,
+    second_id: u64,
+) -> bool {
+    let (Some(first), Some(second)) = (paths.get(&first_id), paths.get(&second_id)) else {
+        return false;
+    };
+
+    first
+        .iter()
+        .zip(second)
+        .take_while(|(a, b)| ptr::eq(**a, **b))
+        .last()
+        .is_some_and(|(pair, _)| pair["horizontal"].as_bool().unwrap_or(true))
+}
+
+/// Parses the output of `wezterm cli list --format json`, keeping the tabs of the window with the
+/// given title, or of the only window.
+///
+/// Wezterm doesn't report foreground commands, so the panes only have their tty.
+pub fn parse_wezterm_list(json: &str, window_title: &str) -> Result<Vec<TerminalTab>> {
+    let panes: Vec<WeztermPane> = serde_json::from_str(json)
+        .map_err(|e| Error::Proc(format!("Invalid wezterm cli list output: {}", e)))?;
+
+    // Wezterm doesn't know the X window ids of its windows
+    let window_id = panes
+        .iter()
+        .find(|pane| pane.window_title.as_deref() == Some(window_title))
+        .or_else(|| {
+            panes
+                .first()
+                .filter(|first| panes.iter().all(|pane| pane.window_id == first.window_id))
+        })
+        .map(|pane| pane.window_id)
+        .ok_or_else(|| {
+            Error::Proc(format!(
+                "Cannot tell which wezterm window is {}",
+                window_title
+            ))
+        })?;
+
+    let mut tabs: Vec<(u64, TerminalTab)> = vec![];
+    let mut previous_top_row = None;
+    for pane in panes.into_iter().filter(|pane| pane.window_id == window_id) {
+        let tab = match tabs.last_mut() {
+            Some((tab_id, tab)) if *tab_id == pane.tab_id => tab,
+            _ => {
+                previous_top_row = None;
+                tabs.push((pane.tab_id, TerminalTab::default()));
+                &mut tabs.last_mut().unwrap().1
+            }
+        };
+
+        tab.panes.push(TerminalPane {
+            cwd: parse_file_url(&pane.cwd),
+            foreground: None,
+            tty: pane.tty_name,
+            beside_previous: previous_top_row == Some(pane.top_row),
+        });
+        previous_top_row = Some(pane.top_row);
+    }
+
+    Ok(tabs.into_iter().map(|(_, tab)| tab).collect())
+}
+
+// Wezterm reports working directories as percent-encoded `file://host/path` URLs
+fn parse_file_url(url: &str) -> String {
+    let Some(rest) = url.strip_prefix("file://") else {
+        return url.to_string();
+    };
+    let path = rest.find('/').map_or("", |i| &rest[i..]).as_bytes();
+
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        let escaped = path
+            .get(i + 1..i + 3)
+            .filter(|_| path[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(path[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// The foreground process group leader of a tty, unless it is the shell leading the session
+fn get_tty_foreground(tty: &str) -> Result<Option<ForegroundProcess>> {
+    // /proc/<pid>/stat encodes the device number of its tty differently than stat(2)
+    let rdev = fs::metadata(tty)?.rdev();
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    let tty_nr = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+
+    for entry in fs::read_dir("/proc")? {
+        let Ok(pid) = entry?.file_name().to_string_lossy().parse::<u32>() else {
+            continue;
+        };
+        // Processes may exit while they are listed
+        let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+            continue;
+        };
+        let Some(fields) = stat.rfind(')').map(|end| {
+            stat[end + 1..]
+                .split_whitespace()
+                .map(|field| field.parse::<i64>().unwrap_or(-1))
+                .collect::<Vec<_>>()
+        }) else {
+            continue;
+        };
+
+        // state ppid pgrp session tty_nr tpgid
+        if fields.len() < 6 || fields[4] != tty_nr as i64 || fields[5] != pid as i64 {
+            continue;
+        }
+        if fields[3] == pid as i64 {
+            return Ok(None);
+        }
+        return Ok(get_process_cmd(pid)
+            .ok()
+            .map(|command| ForegroundProcess { pid, command }));
+    }
+
+    Ok(None)
+}
+
+/// Returns the command attaching again to the tmux or screen session a foreground process is a
+/// client of, which creates the session if it is gone, or `None` if it isn't such a client.
+pub fn get_multiplexer_cmd(process: &ForegroundProcess) -> Result<Option<Vec<String>>> {
+    let Some((program, args)) = process.command.split_first() else {
+        return Ok(None);
+    };
+
+    match program.rsplit('/').next().unwrap_or(program) {
+        "tmux" => get_tmux_cmd(process.pid, program, args),
+        "screen" => get_screen_cmd(process.pid, program, args),
+        _ => Ok(None),
+    }
+}
+
+fn get_tmux_cmd(pid: u32, program: &str, args: &[String]) -> Result<Option<Vec<String>>> {
+    // The server socket is chosen by -S or -L, is the one of the enclosing tmux otherwise, and
+    // is under TMUX_TMPDIR by default
+    let (socket_path, socket_name) = parse_tmux_socket_args(args);
+    let mut env = get_process_env(pid)?;
+    let enclosing_socket = env
+        .remove("TMUX")
+        .and_then(|tmux| tmux.split(',').next().map(String::from))
+        .filter(|_| socket_name.is_none());
+    let socket_path = match socket_path.or(enclosing_socket) {
+        Some(path) => path,
+        None => {
+            let tmpdir = env
+                .remove("TMUX_TMPDIR")
+                .unwrap_or_else(|| "/tmp".to_string());
+            let uid = fs::metadata(format!("/proc/{}", pid))?.uid();
+            format!(
+                "{}/tmux-{}/{}",
+                tmpdir,
+                uid,
+                socket_name.as_deref().unwrap_or("default")
+            )
+        }
+    };
+
+    let clients = run_backend(Command::new(program).args([
+        "-S",
+        &socket_path,
+        "list-clients",
+        "-F",
+        "#{client_pid} #{session_name}",
+    ]))?;
+    let session = clients.lines().find_map(|line| {
+        let (client_pid, session) = line.split_once(' ')?;
+        (client_pid.parse() == Ok(pid)).then_some(session)
+    });
+
+    Ok(session.map(|session| {
+        [
+            program,
+            "-S",
+            &socket_path,
+            "new-session",
+            "-A",
+            "-s",
+            session,
+        ]
+        .map(String::from)
+        .to_vec()
+    }))
+}
+
+// Reads the -S socket path and -L socket name among the options before the tmux command, which
+// may be grouped as in -uL name or given their value inline as in -Lname
+fn parse_tmux_socket_args(args: &[String]) -> (Option<String>, Option<String>) {
+    // The options of tmux taking a value
+    const VALUE_OPTIONS: &[char] = &['c', 'f', 'L', 'S', 'T'];
+
+    let mut socket_path = None;
+    let mut socket_name = None;
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        let Some(options) = arg.strip_prefix('-').filter(|options| !options.is_empty()) else {
+            break;
+        };
+        if options == "-" {
+            break;
+        }
+
+        for (i, option) in options.char_indices() {
+            if !VALUE_OPTIONS.contains(&option) {
+                continue;
+            }
+
+            let inline = &options[i + option.len_utf8()..];
+            let value = if inline.is_empty() {
+                args_iter.next().cloned()
+            } else {
+                Some(inline.to_string())
+            };
+            match option {
+                'S' => socket_path = value,
+                'L' => socket_name = value,
+                _ => {}
+            }
+            break;
+        }
+    }
+
+    (socket_path, socket_name)
+}
+
+fn get_screen_cmd(pid: u32, program: &str, args: &[String]) -> Result<Option<Vec<String>>> {
+    // The session is the argument of -r, -x, -R or -S, possibly grouped as in -dr
+    let named_session = args.windows(2).find_map(|pair| {
+        let is_session_flag = pair[0].starts_with('-')
+            && !pair[0].starts_with("--")
+            && pair[0].ends_with(['r', 'x', 'R', 'S']);
+        (is_session_flag && !pair[1].starts_with('-')).then(|| pair[1].clone())
+    });
+
+    // Otherwise the client started the session, whose server is named after the server pid
+    let session = match named_session {
+        Some(session) => session,
+        None => {
+            let Some(server_pid) = get_first_child_pid(pid)? else {
+                return Ok(None);
+            };
+            if get_process_cmd(server_pid)?.first().map(String::as_str) != Some("SCREEN") {
+                return Ok(None);
+            }
+
+            let sessions = Command::new(program).arg("-ls").output()?.stdout;
+            let prefix = format!("{}.", server_pid);
+            let Some(session) = String::from_utf8_lossy(&sessions)
+                .split_whitespace()
+                .find(|session| session.starts_with(&prefix))
+                .map(String::from)
+            else {
+                return Ok(None);
+            };
+            session
+        }
+    };
+
+    // The pid prefix of a session doesn't survive a reboot, its name does
+    let session = match session.split_once('.') {
+        Some((server_pid, name)) if server_pid.parse::<u32>().is_ok() => name.to_string(),
+        _ => session,
+    };
+
+    let mut cmd = vec![];
+    if let Some(screendir) = get_process_env(pid)?.remove("SCREENDIR") {
+        cmd.extend(["env".to_string(), format!("SCREENDIR={}", screendir)]);
+    }
+    cmd.extend([program.to_string(), "-D".into(), "-R".into(), session]);
+
+    Ok(Some(cmd))
+}
+
+/// Returns an `sh` script to run inside a revived terminal window to open the other tabs and
+/// panes of `tabs`, or `None` when there is a single pane.
+///
+/// The first pane of the first tab is the window itself, and each other pane runs the shell
+/// command returned by `pane_cmd`.
+pub fn rebuild_script(
+    backend: TerminalBackend,
+    tabs: &[TerminalTab],
+    pane_cmd: impl Fn(&TerminalPane) -> Result<String>,
+) -> Result<Option<String>> {
+    if tabs.iter().map(|tab| tab.panes.len()).sum::<usize>() <= 1 {
+        return Ok(None);
+    }
+
+    let cwd_option = |option: &str, pane: &TerminalPane| -> Result<String> {
+        if pane.cwd.is_empty() {
+            return Ok(String::new());
+        }
+        Ok(format!(" {} {}", option, quote(&pane.cwd)?))
+    };
+
+    let mut lines = vec![];
+    match backend {
+        // New windows print their id, which is used to target their tab
+        TerminalBackend::Kitty => {
+            // The layout is set before the other panes are opened, so that each one splits the
+            // previous one, which is active once opened, in the splits layout
+            for (i, tab) in tabs.iter().enumerate() {
+                let target = if i == 0 { "$KITTY_WINDOW_ID" } else { "$w" };
+                let splits = tab.layout.as_deref() == Some("splits");
+                for (j, pane) in tab.panes.iter().enumerate() {
+                    let cwd = cwd_option("--cwd", pane)?;
+                    match (i, j) {
+                        (0, 0) => {}
+                        (_, 0) => lines.push(format!(
+                            "w=$(kitty @ launch --type=tab --match=\"window_id:$KITTY_WINDOW_ID\"{} {})",
+                            cwd,
+                            pane_cmd(pane)?
+                        )),
+                        _ => lines.push(format!(
+                            "kitty @ launch --type=window --match=\"window_id:{}\"{}{} {} >/dev/null",
+                            target,
+                            match (splits, pane.beside_previous) {
+                                (false, _) => "",
+                                (true, true) => " --location=vsplit",
+                                (true, false) => " --location=hsplit",
+                            },
+                            cwd,
+                            pane_cmd(pane)?
+                        )),
+                    }
+
+                    if j == 0 {
+                        if let Some(layout) = &tab.layout {
+                            lines.push(format!(
+                                "kitty @ goto-layout --match=\"window_id:{}\" {}",
+                                target,
+                                quote(layout)?
+                            ));
+                        }
+                    }
+                }
+            }
+            lines.push("kitty @ focus-window --match=\"id:$KITTY_WINDOW_ID\"".to_string());
+        }
+        // New panes print their id, which the next split of the tab splits
+        TerminalBackend::Wezterm => {
+            lines.push("p=\"$WEZTERM_PANE\"".to_string());
+            for (i, tab) in tabs.iter().enumerate() {
+                for (j, pane) in tab.panes.iter().enumerate() {
+                    let cwd = cwd_option("--cwd", pane)?;
+                    match (i, j) {
+                        (0, 0) => continue,
+                        (_, 0) => lines.push(format!(
+                            "p=$(wezterm cli spawn --pane-id \"$WEZTERM_PANE\"{} -- {})",
+                            cwd,
+                            pane_cmd(pane)?
+                        )),
+                        _ => lines.push(format!(
+                            "p=$(wezterm cli split-pane --pane-id \"$p\" {}{} -- {})",
+                            if pane.beside_previous {
+                                "--right"
+                            } else {
+                                "--bottom"
+                            },
+                            cwd,
+                            pane_cmd(pane)?
+                        )),
+                    }
+                }
+            }
+            lines.push("wezterm cli activate-pane --pane-id \"$WEZTERM_PANE\"".to_string());
+        }
+    }
+
+    Ok(Some(lines.join("\n")))
+}
+
+fn quote(s: &str) -> Result<String> {
+    try_quote(s)
+        .map(|quoted| quoted.into_owned())
+        .map_err(|e| Error::Proc(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KITTY_LS: &str = include_str!("../tests/fixtures/kitty_ls.json");
+    const KITTY_LS_SPLITS: &str = include_str!("../tests/fixtures/kitty_ls_splits.json");
+    const WEZTERM_LIST: &str = include_str!("../tests/fixtures/wezterm_list.json");
+
+    fn pane(cwd: &str, foreground: Option<(u32, &[&str])>) -> TerminalPane {
+        TerminalPane {
+            cwd: cwd.to_string(),
+            foreground: foreground.map(|(pid, command)| ForegroundProcess {
+                pid,
+                command: command.iter().map(|s| s.to_string()).collect(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_kitty_ls_keeps_the_tabs_of_the_window() {
+        let tabs = parse_kitty_ls(KITTY_LS, 27262991).unwrap();
+
+        assert_eq!(
+            tabs,
+            vec![
+                TerminalTab {
+                    layout: Some("splits".into()),
+                    panes: vec![
+                        pane("/home/me/notes", Some((4321, &["vim", "notes.md"]))),
+                        pane("/home/me/My Projects", None),
+                    ],
+                },
+                TerminalTab {
+                    layout: Some("stack".into()),
+                    panes: vec![pane("/tmp", Some((4400, &["htop"])))],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_kitty_ls_selects_by_platform_window_id() {
+        let tabs = parse_kitty_ls(KITTY_LS, 27262983).unwrap();
+        assert_eq!(tabs.len(), 1);
+        assert_eq!(tabs[0].panes, vec![pane("/home/me", None)]);
+
+        assert!(parse_kitty_ls(KITTY_LS, 1).is_err());
+    }
+
+    #[test]
+    fn parse_kitty_ls_reads_the_direction_of_splits() {
+        let tabs = parse_kitty_ls(KITTY_LS_SPLITS, 31457287).unwrap();
+
+        assert_eq!(
+            tabs[0]
+                .panes
+                .iter()
+                .map(|pane| pane.beside_previous)
+                .collect::<Vec<_>>(),
+            [false, true, false]
+        );
+    }
+
+    #[test]
+    fn parse_wezterm_list_keeps_the_tabs_of_the_window() {
+        let tabs = parse_wezterm_list(WEZTERM_LIST, "wezterm work").unwrap();
+
+        assert_eq!(tabs.len(), 2);
+        assert_eq!(
+            tabs[0].panes,
+            vec![
+                TerminalPane {
+                    cwd: "/home/me/My Projects/".into(),
+                    tty: Some("/dev/pts/3".into()),
+                    ..Default::default()
+                },
+                TerminalPane {
+                    cwd: "/home/me/src/i3-revive".into(),
+                    tty: Some("/dev/pts/4".into()),
+                    beside_previous: true,
+                    ..Default::default()
+                },
+                TerminalPane::default(),
+            ]
+        );
+        assert_eq!(
+            tabs[1].panes,
+            vec![TerminalPane {
+                cwd: "/tmp".into(),
+                tty: Some("/dev/pts/5".into()),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_wezterm_list_needs_a_title_with_several_windows() {
+        let tabs = parse_wezterm_list(WEZTERM_LIST, "wezterm other").unwrap();
+        assert_eq!(tabs.len(), 1);
+        assert_eq!(tabs[0].panes[0].cwd, "/srv");
+
+        assert!(parse_wezterm_list(WEZTERM_LIST, "unknown").is_err());
+        let single_window = serde_json::to_string(
+            &serde_json::from_str::<Vec<serde_json::Value>>(WEZTERM_LIST).unwrap()[..4],
+        )
+        .unwrap();
+        assert_eq!(
+            parse_wezterm_list(&single_window, "unknown").unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn parse_file_url_decodes_the_path() {
+        assert_eq!(
+            parse_file_url("file://host/a%20b/%C3%A9t%C3%A9"),
+            "/a b/été"
+        );
+        assert_eq!(parse_file_url("file://host/100%/x%2"), "/100%/x%2");
+        assert_eq!(parse_file_url("file://host"), "");
+        assert_eq!(parse_file_url("/plain/path"), "/plain/path");
+    }
+
+    #[test]
+    fn parse_tmux_socket_args_skips_option_values() {
+        let parse = |args: &[&str]| {
+            parse_tmux_socket_args(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+        };
+        let owned = |path: Option<&str>, name: Option<&str>| {
+            (path.map(String::from), name.map(String::from))
+        };
+
+        assert_eq!(parse(&["attach"]), owned(None, None));
+        assert_eq!(
+            parse(&["-f", "conf", "-L", "sock", "attach"]),
+            owned(None, Some("sock"))
+        );
+        assert_eq!(
+            parse(&["-2", "-T", "RGB", "-S", "/run/tmux", "new", "-L", "x"]),
+            owned(Some("/run/tmux"), None)
+        );
+        assert_eq!(
+            parse(&["-uLsock", "-fconf", "attach"]),
+            owned(None, Some("sock"))
+        );
+        assert_eq!(parse(&["-uL", "sock"]), owned(None, Some("sock")));
+        assert_eq!(parse(&["--", "-L", "sock"]), owned(None, None));
+    }
+
+    #[test]
+    fn rebuild_script_is_none_for_a_single_pane() {
+        let tabs = vec![TerminalTab {
+            layout: None,
+            panes: vec![pane("/tmp", None)],
+        }];
+        assert_eq!(
+            rebuild_script(TerminalBackend::Kitty, &tabs, |_| Ok("zsh".into())).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn rebuild_script_for_kitty() {
+        let tabs = parse_kitty_ls(KITTY_LS, 27262991).unwrap();
+        let script = rebuild_script(TerminalBackend::Kitty, &tabs, |pane| {
+            Ok(format!("zsh {}", pane.foreground.is_some()))
+        })
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            script.lines().collect::<Vec<_>>(),
+            [
+                "kitty @ goto-layout --match=\"window_id:$KITTY_WINDOW_ID\" splits",
+                "kitty @ launch --type=window --match=\"window_id:$KITTY_WINDOW_ID\" --location=hsplit --cwd '/home/me/My Projects' zsh false >/dev/null",
+                "w=$(kitty @ launch --type=tab --match=\"window_id:$KITTY_WINDOW_ID\" --cwd /tmp zsh true)",
+                "kitty @ goto-layout --match=\"window_id:$w\" stack",
+                "kitty @ focus-window --match=\"id:$KITTY_WINDOW_ID\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuild_script_for_kitty_splits() {
+        let tabs = parse_kitty_ls(KITTY_LS_SPLITS, 31457287).unwrap();
+        let script = rebuild_script(TerminalBackend::Kitty, &tabs, |pane| {
+            Ok(format!("zsh {}", pane.foreground.is_some()))
+        })
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            script.lines().collect::<Vec<_>>(),
+            [
+                "kitty @ goto-layout --match=\"window_id:$KITTY_WINDOW_ID\" splits",
+                "kitty @ launch --type=window --match=\"window_id:$KITTY_WINDOW_ID\" --location=vsplit --cwd /home/me/src zsh true >/dev/null",
+                "kitty @ launch --type=window --match=\"window_id:$KITTY_WINDOW_ID\" --location=hsplit --cwd /home/me zsh false >/dev/null",
+                "kitty @ focus-window --match=\"id:$KITTY_WINDOW_ID\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuild_script_for_wezterm() {
+        let tabs = parse_wezterm_list(WEZTERM_LIST, "wezterm work").unwrap();
+        let script = rebuild_script(TerminalBackend::Wezterm, &tabs, |_| Ok("zsh".into()))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            script.lines().collect::<Vec<_>>(),
+            [
+                "p=\"$WEZTERM_PANE\"",
+                "p=$(wezterm cli split-pane --pane-id \"$p\" --right --cwd /home/me/src/i3-revive -- zsh)",
+                "p=$(wezterm cli split-pane --pane-id \"$p\" --bottom -- zsh)",
+                "p=$(wezterm cli spawn --pane-id \"$WEZTERM_PANE\" --cwd /tmp -- zsh)",
+                "wezterm cli activate-pane --pane-id \"$WEZTERM_PANE\"",
+            ]
+        );
+    }
+}